@@ -0,0 +1,77 @@
+//! Throughput of signature generation and diffing, run with `cargo bench`
+//!
+//! Each input is timed at several block sizes and reported in MB/s of the new file.
+
+use rolling_hash::{check_diffs, Signature};
+use std::hint::black_box;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+const INPUT_LEN: usize = 1 << 20;
+const BLOCK_SIZES: [usize; 3] = [64, 512, 4096];
+const MIN_TIME: Duration = Duration::from_millis(500);
+
+fn pseudo_random(len: usize) -> Vec<u8> {
+    let mut state: u32 = 0x9E37_79B9;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// Old and new file of each input
+fn inputs() -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+    let random = pseudo_random(INPUT_LEN);
+    let mut other_random = pseudo_random(INPUT_LEN + 1);
+    other_random.remove(0);
+    let repetitive: Vec<u8> = b"abcd".iter().copied().cycle().take(INPUT_LEN).collect();
+    let mut edited = random.clone();
+    edited[INPUT_LEN / 2] ^= 0xff;
+    vec![
+        ("random", random.clone(), other_random),
+        ("repetitive", repetitive.clone(), repetitive),
+        ("single byte edit", random, edited),
+    ]
+}
+
+/// Runs `f` until `MIN_TIME` has passed and prints the throughput over `len` bytes
+fn bench(name: &str, len: usize, mut f: impl FnMut()) {
+    f();
+    let start = Instant::now();
+    let mut runs = 0;
+    while start.elapsed() < MIN_TIME {
+        f();
+        runs += 1;
+    }
+    let per_run = start.elapsed().as_secs_f64() / runs as f64;
+    println!("{:<40} {:>10.2} MB/s", name, len as f64 / per_run / 1e6);
+}
+
+fn main() {
+    for (input, old, new) in inputs() {
+        for &block_size in BLOCK_SIZES.iter() {
+            bench(
+                &format!("generate {} bs {}", input, block_size),
+                old.len(),
+                || {
+                    let mut sig = Signature::new(block_size);
+                    sig.generate(&mut Cursor::new(&old)).unwrap();
+                    black_box(sig);
+                },
+            );
+            bench(
+                &format!("check_diffs {} bs {}", input, block_size),
+                new.len(),
+                || {
+                    black_box(
+                        check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap(),
+                    );
+                },
+            );
+        }
+    }
+}
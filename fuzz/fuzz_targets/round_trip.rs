@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rolling_hash::{apply_delta, check_diffs};
+use std::io::Cursor;
+
+// The first byte picks the block size, the second where the rest is split into the old
+// and the new file.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let block_size = data[0] as usize % 64 + 1;
+    let rest = &data[2..];
+    let (old, new) = rest.split_at(data[1] as usize * rest.len() / 255);
+    if let Ok(deltas) = check_diffs(block_size, Cursor::new(old), Cursor::new(new)) {
+        assert_eq!(apply_delta(old, block_size, &deltas), new);
+    }
+});
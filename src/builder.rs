@@ -0,0 +1,967 @@
+use crate::rollsum::{RollingHash, Rollsum};
+use crate::{
+    coalesce_deltas_capped, delta_spans, recommended_block_size, Add, AddRef, Blake2b, Delete,
+    Delta, DeltaRef, DiffError, DiffIter, Digest, Signature, Watch,
+};
+use std::borrow::Borrow;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicBool;
+
+/// Bytes of the new file between calls of the progress callback, see `diff_with_progress`
+pub const PROGRESS_INTERVAL: u64 = 64 * 1024;
+
+/// Which block is matched when several blocks of the old file hash the same
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchPolicy {
+    /// The block earliest in the old file
+    Smallest,
+    /// The block closest to where the window is in the new file
+    Nearest,
+}
+
+impl MatchPolicy {
+    pub(crate) fn pick(
+        self,
+        block_indexes: impl Iterator<Item = u64>,
+        near_block: u64,
+    ) -> Option<u64> {
+        match self {
+            MatchPolicy::Smallest => block_indexes.min(),
+            MatchPolicy::Nearest => {
+                block_indexes.min_by_key(|block_index| block_index.abs_diff(near_block))
+            }
+        }
+    }
+}
+
+/// Options of a diff, `check_diffs` is a `DiffBuilder` with only the block size set
+///
+/// Unset options default to a block size picked from the old file length, the full strong
+/// hash and coalesced deltas.
+#[derive(Debug)]
+pub struct DiffBuilder<D: Digest = Blake2b, R: RollingHash = Rollsum> {
+    block_size: Option<usize>,
+    strong_hash_len: Option<usize>,
+    coalesce: bool,
+    reuse_blocks: bool,
+    match_policy: MatchPolicy,
+    seed: u64,
+    max_literal_run: Option<usize>,
+    lookahead: Option<usize>,
+    max_deltas: Option<usize>,
+    skip_prefix: usize,
+    skip_suffix: usize,
+    min_match_run: usize,
+    digest: PhantomData<D>,
+    rolling_hash: PhantomData<R>,
+}
+
+impl DiffBuilder {
+    pub fn new() -> Self {
+        Self::with_digest()
+    }
+}
+
+impl Default for DiffBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: Digest, R: RollingHash> DiffBuilder<D, R> {
+    /// Same as `new` but with strong hashes computed by the digest `D` and weak checksums by `R`
+    pub fn with_digest() -> Self {
+        Self {
+            block_size: None,
+            strong_hash_len: None,
+            coalesce: true,
+            reuse_blocks: false,
+            match_policy: MatchPolicy::Smallest,
+            seed: 0,
+            max_literal_run: None,
+            lookahead: None,
+            max_deltas: None,
+            skip_prefix: 0,
+            skip_suffix: 0,
+            min_match_run: 1,
+            digest: PhantomData,
+            rolling_hash: PhantomData,
+        }
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Bytes of the strong hash compared, see `Signature::with_strong_hash_len`
+    pub fn strong_hash_len(mut self, len: usize) -> Self {
+        self.strong_hash_len = Some(len);
+        self
+    }
+
+    /// Whether adjacent deltas are merged, see `coalesce_deltas`
+    pub fn coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    /// Whether a block of the old file can be copied more than once
+    ///
+    /// By default every block is matched at most once and in order, so content repeated
+    /// in the new file is added again. Reused blocks are only described by their `Copy`,
+    /// deltas without the `Copy`s no longer apply then.
+    pub fn reuse_blocks(mut self, reuse_blocks: bool) -> Self {
+        self.reuse_blocks = reuse_blocks;
+        self
+    }
+
+    /// Block taken among candidates hashing the same, `MatchPolicy::Smallest` by default
+    ///
+    /// Only blocks after the last matched one are candidates unless blocks are reused.
+    pub fn match_policy(mut self, policy: MatchPolicy) -> Self {
+        self.match_policy = policy;
+        self
+    }
+
+    /// Seed of the weak checksums of the old file's signature, see `Signature::with_seed`
+    ///
+    /// The deltas are the same whatever the seed, only which blocks share a bucket changes.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Longest run of new bytes in a single `Add`, longer runs are split
+    ///
+    /// Unset, an `Add` can hold the whole new file if nothing matches. With a maximum the
+    /// new bytes are flushed every `max_literal_run` bytes instead of piling up until the
+    /// next match, and coalescing does not merge them again. 0 is taken as 1.
+    pub fn max_literal_run(mut self, max_literal_run: usize) -> Self {
+        self.max_literal_run = Some(max_literal_run);
+        self
+    }
+
+    /// Bytes of the new file read at a time ahead of the window, at least a block
+    ///
+    /// The new file is streamed through a window of a block and a read buffer of 8 KiB by
+    /// default, so besides the deltas a diff holds no more of it than that.
+    pub fn lookahead(mut self, bytes: usize) -> Self {
+        self.lookahead = Some(bytes);
+        self
+    }
+
+    /// Most deltas worth sending, more are replaced by the whole new file
+    ///
+    /// A file changed so much that it takes more than `max_deltas` deltas is sent as a
+    /// `Delete` of the whole old file and an `Add` of the whole new one instead, read again
+    /// from its start. The replacement is at most 2 deltas, more if literal runs are capped.
+    pub fn max_deltas(mut self, max_deltas: usize) -> Self {
+        self.max_deltas = Some(max_deltas);
+        self
+    }
+
+    /// Fewest blocks in a row a match has to run for, shorter ones are sent as new bytes
+    ///
+    /// With small blocks unrelated data matches a block here and there by chance, each
+    /// match splitting a run of new bytes in two. Runs of fewer than `min_match_run`
+    /// contiguous blocks of the old file are added instead, their blocks deleted. A short
+    /// last block counts as a block. 0 is taken as 1, which keeps every match.
+    pub fn min_match_run(mut self, blocks: usize) -> Self {
+        self.min_match_run = blocks;
+        self
+    }
+
+    /// Bytes at the start of both files left out of the diff, e.g. a shared header
+    ///
+    /// The skipped bytes are taken to be the same in both files, no delta refers to them
+    /// and blocks are cut from the first byte after them. Offsets of the deltas are still
+    /// in the whole files, `apply_delta` on the whole old file copies the skipped bytes
+    /// like any other bytes no delta covers. Only the `block_index` of a `Copy` counts from
+    /// the end of the prefix. Not used when diffing against a signature, which is of the
+    /// whole old file.
+    pub fn skip_prefix(mut self, bytes: usize) -> Self {
+        self.skip_prefix = bytes;
+        self
+    }
+
+    /// Bytes at the end of both files left out of the diff, see `skip_prefix`
+    pub fn skip_suffix(mut self, bytes: usize) -> Self {
+        self.skip_suffix = bytes;
+        self
+    }
+
+    /// Diffs the new file against the old one
+    ///
+    /// Coalesced deltas of identical files are empty instead of a single `Copy` of the
+    /// whole old file.
+    pub fn diff(
+        &self,
+        old_buf: Cursor<&[u8]>,
+        new_buf: impl Read + Seek,
+    ) -> Result<Vec<Delta>, DiffError> {
+        self.diff_watched(old_buf, new_buf, Watch::default())
+    }
+
+    /// Same as `diff`, calling `progress` with the bytes of the new file passed and its length
+    ///
+    /// It is called every `PROGRESS_INTERVAL` bytes the window moves and once the whole
+    /// new file is diffed, with both counts the same then. Skipped bytes are not counted.
+    pub fn diff_with_progress(
+        &self,
+        old_buf: Cursor<&[u8]>,
+        new_buf: impl Read + Seek,
+        progress: Option<&mut dyn FnMut(u64, u64)>,
+    ) -> Result<Vec<Delta>, DiffError> {
+        let watch = Watch {
+            progress,
+            ..Watch::default()
+        };
+        self.diff_watched(old_buf, new_buf, watch)
+    }
+
+    /// Same as `diff`, failing with `DiffError::Cancelled` once `cancel` is set
+    ///
+    /// The flag is checked before every step of the window, so the diff stops within a
+    /// block of the new file, e.g. when set from another thread.
+    pub fn diff_cancellable(
+        &self,
+        old_buf: Cursor<&[u8]>,
+        new_buf: impl Read + Seek,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Vec<Delta>, DiffError> {
+        let watch = Watch {
+            cancel,
+            ..Watch::default()
+        };
+        self.diff_watched(old_buf, new_buf, watch)
+    }
+
+    fn diff_watched(
+        &self,
+        old_buf: Cursor<&[u8]>,
+        mut new_buf: impl Read + Seek,
+        watch: Watch<'_>,
+    ) -> Result<Vec<Delta>, DiffError> {
+        let old = self.skipped(old_buf.into_inner());
+        let new_len = new_buf.seek(SeekFrom::End(0))?;
+        let new_buf = Skipped {
+            inner: new_buf,
+            start: (self.skip_prefix as u64).min(new_len),
+            end: new_len.saturating_sub(self.skip_suffix as u64),
+        };
+        let block_size = self
+            .block_size
+            .unwrap_or_else(|| recommended_block_size(old.len()));
+        let strong_hash_len = self.strong_hash_len.unwrap_or_else(D::output_size);
+        let iter = DiffIter::<D, R, _>::new(
+            block_size,
+            strong_hash_len,
+            self.seed,
+            Cursor::new(old),
+            new_buf,
+        );
+        let deltas = self.finish(iter, old.len() as u64, watch)?;
+        Ok(self.unskipped(deltas))
+    }
+
+    /// Same as `diff` with the content of `Add`s borrowed from `new_buf` instead of copied
+    pub fn diff_borrowed<'a>(
+        &self,
+        old_buf: Cursor<&[u8]>,
+        new_buf: &'a [u8],
+    ) -> Result<Vec<DeltaRef<'a>>, DiffError> {
+        let old = self.skipped(old_buf.into_inner());
+        let block_size = self
+            .block_size
+            .unwrap_or_else(|| recommended_block_size(old.len()));
+        let strong_hash_len = self.strong_hash_len.unwrap_or_else(D::output_size);
+        let iter = DiffIter::<D, R, _>::new(
+            block_size,
+            strong_hash_len,
+            self.seed,
+            Cursor::new(old),
+            Cursor::new(self.skipped(new_buf)),
+        );
+        let deltas = self.finish(iter.without_content(), old.len() as u64, Watch::default())?;
+        Ok(self
+            .unskipped(deltas)
+            .into_iter()
+            .map(|delta| match delta {
+                Delta::Add(add) => {
+                    let start = add.byte_index as usize;
+                    DeltaRef::Add(AddRef {
+                        byte_index: add.byte_index,
+                        content: &new_buf[start..start + add.bytes as usize],
+                    })
+                }
+                Delta::Delete(delete) => DeltaRef::Delete(delete),
+                Delta::Copy(copy) => DeltaRef::Copy(copy),
+            })
+            .collect())
+    }
+
+    /// Diffs the new file against a signature of the old one, see `diff_against_signature`
+    ///
+    /// The block size and strong hash length are the signature's, the ones set here are
+    /// not used.
+    pub fn diff_against_signature(
+        &self,
+        sig: &Signature<D, R>,
+        new_buf: impl Read + Seek,
+    ) -> Result<Vec<Delta>, DiffError> {
+        let iter = DiffIter::with_signature(sig, new_buf);
+        self.finish(iter, sig.get_file_size(), Watch::default())
+    }
+
+    /// The bytes of `file` between the skipped prefix and suffix
+    fn skipped<'a>(&self, file: &'a [u8]) -> &'a [u8] {
+        let start = self.skip_prefix.min(file.len());
+        let end = file.len().saturating_sub(self.skip_suffix).max(start);
+        &file[start..end]
+    }
+
+    /// Moves deltas of the files without the skipped prefix to the whole files
+    fn unskipped(&self, mut deltas: Vec<Delta>) -> Vec<Delta> {
+        let prefix = self.skip_prefix as u64;
+        for delta in &mut deltas {
+            match delta {
+                Delta::Add(add) => add.byte_index += prefix,
+                Delta::Delete(delete) => delete.byte_index += prefix,
+                Delta::Copy(copy) => copy.src_byte_index += prefix,
+            }
+        }
+        deltas
+    }
+
+    /// Collects and checks the deltas, coalesced if set
+    fn finish<N: Read + Seek, S: Borrow<Signature<D, R>>>(
+        &self,
+        iter: DiffIter<D, R, N, S>,
+        old_len: u64,
+        mut watch: Watch<'_>,
+    ) -> Result<Vec<Delta>, DiffError> {
+        let max_literal_run = self.max_literal_run.unwrap_or(usize::MAX);
+        let iter = match self.lookahead {
+            Some(bytes) => iter.reading_ahead(bytes),
+            None => iter,
+        };
+        let mut iter = iter
+            .reusing_blocks(self.reuse_blocks)
+            .matching_by(self.match_policy)
+            .capping_literals(max_literal_run);
+        let mut deltas = Vec::new();
+        while let Some(delta) = iter.next_watched(&mut watch) {
+            deltas.push(delta?);
+        }
+        watch.done(iter.new_len);
+        Delta::validate(&deltas).map_err(DiffError::Overlap)?;
+        let deltas = match self.min_match_run {
+            0 | 1 => deltas,
+            min_match_run => without_short_matches(
+                deltas,
+                min_match_run,
+                iter.new_buf.get_mut(),
+                iter.keep_content,
+                max_literal_run.max(1),
+            )?,
+        };
+        let deltas = match self.coalesce {
+            true => coalesce_deltas_capped(deltas, max_literal_run.max(1) as u64),
+            false => deltas,
+        };
+        let deltas = match &deltas[..] {
+            [Delta::Copy(copy)]
+                if self.coalesce && copy.src_byte_index == 0 && copy.bytes == old_len =>
+            {
+                Vec::new()
+            }
+            _ => deltas,
+        };
+        match self.max_deltas {
+            Some(max_deltas) if deltas.len() > max_deltas => Ok(whole_file(
+                iter.new_buf.into_inner(),
+                old_len,
+                max_literal_run.max(1),
+            )?),
+            _ => Ok(deltas),
+        }
+    }
+}
+
+/// `deltas` with runs of fewer than `min_match_run` contiguous `Copy`s replaced by the new
+/// bytes they copied, read again from `new_buf`, and `Delete`s of the blocks they consumed
+fn without_short_matches<N: Read + Seek>(
+    deltas: Vec<Delta>,
+    min_match_run: usize,
+    new_buf: &mut N,
+    keep_content: bool,
+    max_literal_run: usize,
+) -> io::Result<Vec<Delta>> {
+    let spans = delta_spans(&deltas);
+    let mut short = vec![false; deltas.len()];
+    let mut run_start = 0;
+    for index in 0..=deltas.len() {
+        let continues = match (deltas.get(index.wrapping_sub(1)), deltas.get(index)) {
+            (Some(Delta::Copy(last)), Some(Delta::Copy(copy))) => {
+                last.src_byte_index + last.bytes == copy.src_byte_index
+            }
+            _ => false,
+        };
+        if continues {
+            continue;
+        }
+        let is_run = matches!(deltas.get(run_start), Some(Delta::Copy(_)));
+        if is_run && index - run_start < min_match_run {
+            short[run_start..index].fill(true);
+        }
+        run_start = index;
+    }
+
+    let mut kept = Vec::with_capacity(deltas.len());
+    // the changes since the last kept `Copy`, deletions first so new bytes run together
+    let mut deletes = Vec::new();
+    let mut adds = Vec::new();
+    // end of the old file consumed so far, reused blocks are not deleted
+    let mut consumed = 0;
+    for ((delta, short), (old, new)) in deltas.into_iter().zip(short).zip(spans) {
+        if let Delta::Copy(_) | Delta::Delete(_) = delta {
+            if short && old.start >= consumed {
+                deletes.push(Delta::Delete(Delete {
+                    byte_index: old.start,
+                    bytes: old.end - old.start,
+                }));
+            }
+            consumed = consumed.max(old.end);
+        }
+        match delta {
+            Delta::Copy(_) if !short => {
+                kept.append(&mut deletes);
+                kept.append(&mut adds);
+                kept.push(delta);
+                continue;
+            }
+            Delta::Delete(_) => {
+                deletes.push(delta);
+                continue;
+            }
+            Delta::Add(_) => {
+                adds.push(delta);
+                continue;
+            }
+            Delta::Copy(_) => {}
+        }
+        if keep_content {
+            new_buf.seek(SeekFrom::Start(new.start))?;
+        }
+        let mut byte_index = new.start;
+        while byte_index < new.end {
+            let mut add = Add::new(byte_index);
+            let bytes = (new.end - byte_index).min(max_literal_run as u64);
+            match keep_content {
+                true => {
+                    let mut run = vec![0; bytes as usize];
+                    new_buf.read_exact(&mut run)?;
+                    add.extend(&run);
+                }
+                false => add.bytes = bytes,
+            }
+            byte_index += bytes;
+            adds.push(Delta::Add(add));
+        }
+    }
+    kept.append(&mut deletes);
+    kept.append(&mut adds);
+    Ok(kept)
+}
+
+/// Deltas replacing all of the old file by the new one, read again from its start
+fn whole_file<N: Read + Seek>(
+    mut new_buf: N,
+    old_len: u64,
+    max_literal_run: usize,
+) -> io::Result<Vec<Delta>> {
+    let mut new = Vec::new();
+    new_buf.seek(SeekFrom::Start(0))?;
+    new_buf.read_to_end(&mut new)?;
+    let mut deltas = Vec::new();
+    if old_len > 0 {
+        deltas.push(Delta::Delete(Delete {
+            byte_index: 0,
+            bytes: old_len,
+        }));
+    }
+    let mut byte_index = 0;
+    for run in new.chunks(max_literal_run) {
+        let mut add = Add::new(byte_index);
+        add.extend(run);
+        byte_index += add.bytes();
+        deltas.push(Delta::Add(add));
+    }
+    Ok(deltas)
+}
+
+/// The bytes of `inner` from `start` to `end`, read and sought as a file of their own
+struct Skipped<N> {
+    inner: N,
+    start: u64,
+    end: u64,
+}
+
+impl<N: Read + Seek> Read for Skipped<N> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let position = self.inner.stream_position()?;
+        let left = self.end.saturating_sub(position) as usize;
+        let n = buf.len().min(left);
+        self.inner.read(&mut buf[..n])
+    }
+}
+
+impl<N: Seek> Seek for Skipped<N> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let len = self.end.saturating_sub(self.start);
+        let position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => (len as i64 + offset) as u64,
+            SeekFrom::Current(offset) => {
+                let position = self.inner.stream_position()? - self.start;
+                (position as i64 + offset) as u64
+            }
+        };
+        let position = self.inner.seek(SeekFrom::Start(self.start + position))?;
+        Ok(position - self.start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::pseudo_random;
+    use crate::{apply_delta, check_diffs, Blake2s, RabinKarp};
+    use std::cell::Cell;
+
+    /// A new file of `len` bytes repeating `pattern`, generated as it is read
+    struct Repeating<'a> {
+        pattern: &'a [u8],
+        len: u64,
+        position: u64,
+        /// longest read asked for
+        max_read: &'a Cell<usize>,
+    }
+
+    impl Read for Repeating<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.max_read.set(self.max_read.get().max(buf.len()));
+            let n = buf.len().min((self.len - self.position) as usize);
+            for byte in &mut buf[..n] {
+                *byte = self.pattern[(self.position % self.pattern.len() as u64) as usize];
+                self.position += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Seek for Repeating<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+                SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+            };
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn defaults_match_check_diffs() {
+        let old = pseudo_random(16_384);
+        let mut new = old.clone();
+        new.splice(4096..4096, b"not ".iter().cloned());
+        let built = DiffBuilder::new()
+            .block_size(64)
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        let diffs = check_diffs(64, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        assert_eq!(built, diffs);
+
+        // block size picked from the old file
+        let auto = DiffBuilder::default()
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        assert_eq!(apply_delta(&old, 128, &auto), new);
+    }
+
+    #[test]
+    fn configured_diffs_round_trip() {
+        let old = pseudo_random(10_000);
+        let mut new = old.clone();
+        new.splice(5_000..5_008, b"Alohomora".iter().cloned());
+
+        let uncoalesced = DiffBuilder::<Blake2s, RabinKarp>::with_digest()
+            .block_size(32)
+            .strong_hash_len(4)
+            .coalesce(false)
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        // 313 blocks with the short last one, all but the edited one are copied
+        let copies = uncoalesced
+            .iter()
+            .filter(|delta| matches!(delta, Delta::Copy(_)))
+            .count();
+        assert_eq!(copies, 312);
+        assert_eq!(apply_delta(&old, 32, &uncoalesced), new);
+
+        let coalesced = DiffBuilder::<Blake2s, RabinKarp>::with_digest()
+            .block_size(32)
+            .strong_hash_len(4)
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        assert!(coalesced.len() < uncoalesced.len());
+        assert_eq!(apply_delta(&old, 32, &coalesced), new);
+    }
+
+    #[test]
+    fn reused_blocks_are_copied_again() {
+        let old = pseudo_random(64 * 8);
+        let mut new = old.clone();
+        new.extend_from_slice(&old[64..128]);
+
+        let once = DiffBuilder::new()
+            .block_size(64)
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        assert!(matches!(once.last(), Some(Delta::Add(_))));
+
+        let reused = DiffBuilder::new()
+            .block_size(64)
+            .reuse_blocks(true)
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        assert!(!reused.iter().any(|delta| matches!(delta, Delta::Add(_))));
+        match reused.last() {
+            Some(Delta::Copy(copy)) => {
+                assert_eq!(copy.block_index(), 1);
+                assert_eq!(copy.bytes(), 64);
+            }
+            _ => panic!("Should be copy"),
+        }
+        assert_eq!(apply_delta(&old, 64, &reused), new);
+    }
+
+    #[test]
+    fn seeded_diffs_match_unseeded_ones() {
+        for (block_size, old, new) in crate::tests::corpus() {
+            let diff = |seed| {
+                DiffBuilder::new()
+                    .block_size(block_size)
+                    .seed(seed)
+                    .diff(Cursor::new(&old), Cursor::new(&new))
+                    .unwrap()
+            };
+            assert_eq!(diff(1), diff(0));
+            assert_eq!(diff(0x5eed), diff(0));
+
+            let mut sig = Signature::new(block_size).with_seed(7);
+            sig.generate(&mut Cursor::new(&old)).unwrap();
+            let diffs = DiffBuilder::new().diff_against_signature(&sig, Cursor::new(&new));
+            assert_eq!(diffs.unwrap(), diff(0));
+        }
+    }
+
+    #[test]
+    fn large_new_files_are_read_through_the_lookahead() {
+        let old = pseudo_random(64 * 1_024);
+        let max_read = Cell::new(0);
+        let new = Repeating {
+            pattern: &old,
+            len: 8 * 1_024 * 1_024,
+            position: 0,
+            max_read: &max_read,
+        };
+        let diffs = DiffBuilder::new()
+            .block_size(1_024)
+            .reuse_blocks(true)
+            .lookahead(2_048)
+            .diff(Cursor::new(&old), new)
+            .unwrap();
+        assert!(max_read.get() <= 2_048);
+        // every repetition is a single copy of the whole old file
+        assert_eq!(diffs.len(), 128);
+        assert!(diffs
+            .iter()
+            .all(|delta| matches!(delta, Delta::Copy(copy) if copy.bytes() == old.len() as u64)));
+
+        // at least a block is read ahead
+        max_read.set(0);
+        let new = Repeating {
+            pattern: &old,
+            len: 1_024 * 1_024,
+            position: 0,
+            max_read: &max_read,
+        };
+        let small = DiffBuilder::new()
+            .block_size(1_024)
+            .reuse_blocks(true)
+            .lookahead(16)
+            .diff(Cursor::new(&old), new)
+            .unwrap();
+        assert_eq!(small.len(), 16);
+        assert_eq!(max_read.get(), 1_024);
+    }
+
+    #[test]
+    fn too_many_deltas_fall_back_to_the_whole_file() {
+        // every few bytes of the new file match a block of the old one, the rest differs
+        let old = pseudo_random(4_096);
+        let mut new = Vec::new();
+        for (i, block) in old.chunks(8).enumerate() {
+            new.extend_from_slice(block);
+            new.push(i as u8);
+        }
+        let incremental = DiffBuilder::new()
+            .block_size(8)
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        assert!(incremental.len() > 100);
+
+        let capped = DiffBuilder::new()
+            .block_size(8)
+            .max_deltas(100)
+            .diff(Cursor::new(&old), Cursor::new(&new))
+            .unwrap();
+        assert_eq!(
+            capped,
+            vec![
+                Delta::Delete(Delete {
+                    byte_index: 0,
+                    bytes: old.len() as u64,
+                }),
+                Delta::Add(Add {
+                    byte_index: 0,
+                    bytes: new.len() as u64,
+                    content: new.clone(),
+                }),
+            ]
+        );
+        assert_eq!(apply_delta(&old, 8, &capped), new);
+
+        // unrelated buffers are replaced whole, few deltas are kept as they are
+        let unrelated = pseudo_random(9_000)[4_096..].to_vec();
+        let deltas = DiffBuilder::new()
+            .block_size(8)
+            .max_deltas(1)
+            .diff(Cursor::new(&old), Cursor::new(&unrelated))
+            .unwrap();
+        assert!(
+            matches!(&deltas[..], [Delta::Delete(_), Delta::Add(add)] if add.bytes() == unrelated.len() as u64)
+        );
+        assert_eq!(apply_delta(&old, 8, &deltas), unrelated);
+        let kept = DiffBuilder::new()
+            .block_size(8)
+            .max_deltas(2)
+            .diff(Cursor::new(&old), Cursor::new(&old[8..]))
+            .unwrap();
+        assert!(matches!(&kept[..], [Delta::Delete(_), Delta::Copy(_)]));
+    }
+
+    #[test]
+    fn progress_is_reported_up_to_the_new_file_length() {
+        let old = pseudo_random(300_000);
+        let mut new = old.clone();
+        new.splice(100_000..100_000, b"Lumos".iter().cloned());
+        let mut calls = Vec::new();
+        let deltas = DiffBuilder::new()
+            .block_size(64)
+            .diff_with_progress(
+                Cursor::new(&old),
+                Cursor::new(&new),
+                Some(&mut |passed, total| calls.push((passed, total))),
+            )
+            .unwrap();
+        assert_eq!(apply_delta(&old, 64, &deltas), new);
+        assert!(calls.len() >= 4);
+        assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(calls.iter().all(|(_, total)| *total == new.len() as u64));
+        assert_eq!(calls.last(), Some(&(new.len() as u64, new.len() as u64)));
+    }
+
+    #[test]
+    fn set_cancel_flags_stop_the_diff() {
+        let old = pseudo_random(16_384);
+        let mut new = old.clone();
+        new.splice(4096..4096, b"not ".iter().cloned());
+        let builder = DiffBuilder::new().block_size(64);
+
+        let cancel = AtomicBool::new(true);
+        assert_eq!(
+            builder.diff_cancellable(Cursor::new(&old), Cursor::new(&new), Some(&cancel)),
+            Err(DiffError::Cancelled)
+        );
+        let cancel = AtomicBool::new(false);
+        let deltas = builder
+            .diff_cancellable(Cursor::new(&old), Cursor::new(&new), Some(&cancel))
+            .unwrap();
+        assert_eq!(
+            deltas,
+            builder.diff(Cursor::new(&old), Cursor::new(&new)).unwrap()
+        );
+    }
+
+    #[test]
+    fn short_matches_are_sent_as_new_bytes() {
+        let old = pseudo_random(16 * 200);
+        let noise: Vec<u8> = pseudo_random(1_000)
+            .iter()
+            .map(|byte| !byte ^ 0x5a)
+            .collect();
+        let mut new = noise[..300].to_vec();
+        // single blocks matching by chance amid new bytes, then a long shared run
+        new.extend_from_slice(&old[16 * 10..16 * 11]);
+        new.extend_from_slice(&noise[300..500]);
+        new.extend_from_slice(&old[16 * 50..16 * 51]);
+        new.extend_from_slice(&noise[500..600]);
+        new.extend_from_slice(&old[16 * 100..16 * 120]);
+        new.extend_from_slice(&noise[600..650]);
+
+        let diff = |min_match_run| {
+            DiffBuilder::new()
+                .block_size(16)
+                .min_match_run(min_match_run)
+                .diff(Cursor::new(&old), Cursor::new(&new))
+                .unwrap()
+        };
+        let every_match = diff(1);
+        let long_matches = diff(3);
+        assert_eq!(apply_delta(&old, 16, &every_match), new);
+        assert_eq!(apply_delta(&old, 16, &long_matches), new);
+        assert!(long_matches.len() < every_match.len());
+        let copies: Vec<&Delta> = long_matches
+            .iter()
+            .filter(|delta| matches!(delta, Delta::Copy(_)))
+            .collect();
+        assert!(matches!(
+            copies[..],
+            [Delta::Copy(copy)] if copy.src_byte_index() == 1_600 && copy.bytes() == 320
+        ));
+
+        let borrowed = DiffBuilder::new()
+            .block_size(16)
+            .min_match_run(3)
+            .diff_borrowed(Cursor::new(&old), &new)
+            .unwrap();
+        assert_eq!(
+            borrowed
+                .into_iter()
+                .map(|delta| delta.into_owned())
+                .collect::<Vec<_>>(),
+            long_matches
+        );
+    }
+
+    #[test]
+    fn skipped_headers_are_left_out_of_the_deltas() {
+        let header = b"\x89PNG\r\n\x1a\n";
+        let trailer = b"IEND";
+        let body = pseudo_random(4_096);
+        let mut new_body = body.clone();
+        new_body.splice(1_000..1_003, b"Lumos".iter().cloned());
+        let file = |body: &[u8], version: u8| {
+            let mut file = header.to_vec();
+            file.push(version);
+            file.extend_from_slice(body);
+            file.extend_from_slice(trailer);
+            file
+        };
+        let builder = DiffBuilder::new()
+            .block_size(64)
+            .skip_prefix(header.len())
+            .skip_suffix(trailer.len());
+        let old = file(&body, 1);
+        // the version byte right after the header is not skipped, an unchanged one
+        // leaves a `Copy` as the first delta
+        for &version in &[2, 1] {
+            let new = file(&new_body, version);
+            let deltas = builder.diff(Cursor::new(&old), Cursor::new(&new)).unwrap();
+            let borrowed = builder.diff_borrowed(Cursor::new(&old), &new).unwrap();
+            assert_eq!(
+                borrowed
+                    .into_iter()
+                    .map(|delta| delta.into_owned())
+                    .collect::<Vec<_>>(),
+                deltas
+            );
+            let old_end = (old.len() - trailer.len()) as u64;
+            for delta in &deltas {
+                assert!(delta.byte_index() >= header.len() as u64);
+                if let Delta::Copy(copy) = delta {
+                    assert!(copy.src_byte_index() + copy.bytes() <= old_end);
+                }
+            }
+            assert_eq!(
+                matches!(deltas[0], Delta::Copy(_)),
+                version == 1,
+                "{:?}",
+                deltas[0]
+            );
+            assert_eq!(apply_delta(&old, 64, &deltas), new);
+        }
+    }
+
+    #[test]
+    fn literal_runs_are_capped() {
+        let old = pseudo_random(4_096);
+        let new: Vec<u8> = old.iter().map(|byte| !byte).collect();
+        for coalesce in [false, true] {
+            let diffs = DiffBuilder::new()
+                .block_size(64)
+                .max_literal_run(1_000)
+                .coalesce(coalesce)
+                .diff(Cursor::new(&old), Cursor::new(&new))
+                .unwrap();
+            let adds: Vec<u64> = diffs
+                .iter()
+                .filter(|delta| matches!(delta, Delta::Add(_)))
+                .map(Delta::bytes)
+                .collect();
+            assert_eq!(adds, [1_000, 1_000, 1_000, 1_000, 96]);
+            assert_eq!(apply_delta(&old, 64, &diffs), new);
+        }
+
+        let borrowed = DiffBuilder::new()
+            .block_size(64)
+            .max_literal_run(1_000)
+            .diff_borrowed(Cursor::new(&old), &new)
+            .unwrap();
+        assert_eq!(borrowed.len(), 6);
+    }
+
+    #[test]
+    fn match_policy_picks_among_repeated_blocks() {
+        // blocks 0 and 4 are the same
+        let mut old = pseudo_random(64 * 5);
+        let repeated = old[..64].to_vec();
+        old[256..].copy_from_slice(&repeated);
+        // the repeated block comes fourth in the new file
+        let mut new: Vec<u8> = old[..192].iter().map(|b| !b).collect();
+        new.extend_from_slice(&repeated);
+
+        let copied_block = |policy| {
+            let diffs = DiffBuilder::new()
+                .block_size(64)
+                .match_policy(policy)
+                .diff(Cursor::new(&old), Cursor::new(&new))
+                .unwrap();
+            assert_eq!(apply_delta(&old, 64, &diffs), new);
+            diffs
+                .iter()
+                .find_map(|delta| match delta {
+                    Delta::Copy(copy) => Some(copy.block_index()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(copied_block(MatchPolicy::Smallest), 0);
+        assert_eq!(copied_block(MatchPolicy::Nearest), 4);
+    }
+}
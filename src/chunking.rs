@@ -0,0 +1,362 @@
+use crate::rabin_karp::RabinKarp;
+use crate::rollsum::RollingHash;
+#[cfg(feature = "std")]
+use crate::rollsum::Rollsum;
+#[cfg(feature = "std")]
+use crate::signature::Normalizer;
+#[cfg(feature = "std")]
+use crate::{
+    check_strong_hash, coalesce_deltas, Add, Blake2b, Copy, Delete, Delta, Digest, MatchPolicy,
+    Signature,
+};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::Cursor;
+#[cfg(feature = "std")]
+use std::mem;
+
+/// Bytes the boundary hash looks back on
+const WINDOW: usize = 32;
+
+/// Content defined chunking, a block ends where the hash of its last bytes hits a pattern
+///
+/// Boundaries follow the content instead of fixed offsets, so inserting or removing a few
+/// bytes only changes the blocks around the edit. Blocks are between a quarter and four
+/// times the average size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentDefinedChunking {
+    /// expected block length, a power of two
+    avg_size: usize,
+}
+
+impl ContentDefinedChunking {
+    /// `avg_size` is rounded up to a power of two of at least 64 bytes
+    pub fn new(avg_size: usize) -> Self {
+        Self {
+            avg_size: avg_size.max(64).next_power_of_two(),
+        }
+    }
+
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.avg_size / 4
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.avg_size * 4
+    }
+
+    /// Lengths of the blocks `buf` is cut into, in order
+    pub fn block_lens(&self, buf: &[u8]) -> Vec<usize> {
+        let mut lens = Vec::new();
+        let mut start = 0;
+        let mut window: Option<RabinKarp> = None;
+        for i in 0..buf.len() {
+            if i + 1 >= WINDOW {
+                match window.as_mut() {
+                    Some(window) => window.roll_hash(Some(buf[i]), buf[i - WINDOW]),
+                    None => window = Some(RabinKarp::new(&buf[..WINDOW])),
+                }
+            }
+            let len = i + 1 - start;
+            let boundary = len >= self.max_size()
+                || (len >= self.min_size()
+                    && window
+                        .as_ref()
+                        .is_some_and(|w| self.is_boundary(w.digest())));
+            if boundary {
+                lens.push(len);
+                start = i + 1;
+            }
+        }
+        if start < buf.len() {
+            lens.push(buf.len() - start);
+        }
+        lens
+    }
+
+    /// The top log2(avg_size) bits of the mixed digest are all zero
+    fn is_boundary(&self, digest: u32) -> bool {
+        let bits = self.avg_size.trailing_zeros();
+        digest.wrapping_mul(0x9E37_79B1) >> (32 - bits) == 0
+    }
+}
+
+/// Same as `check_diffs` with both files cut into blocks by content
+///
+/// Blocks of the new file are only looked up at their boundaries instead of rolling over
+/// every byte, a changed block is added whole.
+#[cfg(feature = "std")]
+pub fn check_diffs_chunked(
+    chunking: ContentDefinedChunking,
+    old_buf: Cursor<&[u8]>,
+    new_buf: Cursor<&[u8]>,
+) -> Vec<Delta> {
+    diff_chunked::<Blake2b, Rollsum>(chunking, old_buf, new_buf)
+}
+
+/// Same as `check_diffs_chunked` with both files cut into lines
+///
+/// A block ends after every `\n`, so deltas start and end at line boundaries and a changed
+/// line is added whole.
+#[cfg(feature = "std")]
+pub fn diff_lines(old: &[u8], new: &[u8]) -> Vec<Delta> {
+    let mut sig = Signature::<Blake2b, Rollsum>::with_digest(0);
+    sig.generate_cut(old, &line_lens(old));
+    diff_cut(&sig, new, &line_lens(new))
+}
+
+/// Same as `diff_lines` with lines compared as `normalize` maps them
+///
+/// A line of the new file equal to one of the old file once both are normalized is copied
+/// from the old file, so the deltas rebuild the old line in its place. Added lines are
+/// kept as they are in the new file. The rebuilt file is thus only equal to the new one
+/// once both are normalized, a new file equal to the whole old one that way has no deltas.
+/// See `Signature::with_normalizer`.
+#[cfg(feature = "std")]
+pub fn diff_lines_normalized(old: &[u8], new: &[u8], normalize: Normalizer) -> Vec<Delta> {
+    let mut sig = Signature::<Blake2b, Rollsum>::with_digest(0).with_normalizer(normalize);
+    sig.generate_cut(old, &line_lens(old));
+    diff_cut(&sig, new, &line_lens(new))
+}
+
+/// Lengths of the lines of `buf` with their `\n`, the last one may lack it
+#[cfg(feature = "std")]
+fn line_lens(buf: &[u8]) -> Vec<usize> {
+    buf.split_inclusive(|byte| *byte == b'\n')
+        .map(<[u8]>::len)
+        .collect()
+}
+
+#[cfg(feature = "std")]
+fn diff_chunked<D: Digest, R: RollingHash>(
+    chunking: ContentDefinedChunking,
+    mut old_buf: Cursor<&[u8]>,
+    new_buf: Cursor<&[u8]>,
+) -> Vec<Delta> {
+    let mut sig = Signature::<D, R>::with_digest(0);
+    // reading a cursor cannot fail
+    let _ = sig.generate_chunked(&mut old_buf, chunking);
+    let new = *new_buf.get_ref();
+    diff_cut(&sig, new, &chunking.block_lens(new))
+}
+
+/// Diffs `new` cut into blocks of `new_lens` bytes against the signature of the old file
+#[cfg(feature = "std")]
+fn diff_cut<D: Digest, R: RollingHash>(
+    sig: &Signature<D, R>,
+    new: &[u8],
+    new_lens: &[usize],
+) -> Vec<Delta> {
+    let mut deltas = Vec::new();
+    let mut consumed_block_index: i64 = -1;
+    let mut new_bytes = Add::new(0);
+    let mut offset = 0;
+    // end of the rebuilt file so far, behind `offset` if normalized blocks differ in length
+    let mut rebuilt = 0;
+    for &len in new_lens {
+        let block = &new[offset..offset + len];
+        let matched_index = sig
+            .get_chunk_map(sig.weak_checksum(block))
+            .and_then(|strong_hashes| {
+                check_strong_hash::<D>(
+                    consumed_block_index,
+                    (&sig.normalized(block), &[]),
+                    strong_hashes,
+                    sig.get_strong_hash_len(),
+                    false,
+                    MatchPolicy::Smallest,
+                    0,
+                )
+            });
+        offset += len;
+        match matched_index {
+            Some(matched_index) => {
+                // skipped old blocks are deleted in one go
+                let first_skipped = (consumed_block_index + 1) as u64;
+                if matched_index > first_skipped {
+                    let start = sig.get_block_range(first_skipped).start;
+                    deltas.push(Delta::Delete(Delete {
+                        byte_index: start,
+                        bytes: sig.get_block_range(matched_index).start - start,
+                    }));
+                }
+                consumed_block_index = matched_index as i64;
+                let range = sig.get_block_range(matched_index);
+                rebuilt += range.end - range.start;
+                let literal = mem::replace(&mut new_bytes, Add::new(rebuilt));
+                if literal.bytes > 0 {
+                    deltas.push(Delta::Add(literal));
+                }
+                deltas.push(Delta::Copy(Copy {
+                    src_byte_index: range.start,
+                    bytes: range.end - range.start,
+                    block_index: matched_index,
+                }));
+            }
+            None => {
+                new_bytes.extend(block);
+                rebuilt += len as u64;
+            }
+        }
+    }
+    if new_bytes.bytes > 0 {
+        deltas.push(Delta::Add(new_bytes));
+    }
+    if sig.get_blocks() as i64 - 1 > consumed_block_index {
+        let start = sig.get_block_range((consumed_block_index + 1) as u64).start;
+        deltas.push(Delta::Delete(Delete {
+            byte_index: start,
+            bytes: sig.get_file_size() - start,
+        }));
+    }
+    // a new file matching the whole old file needs no deltas, like in `DiffBuilder`
+    let deltas = coalesce_deltas(deltas);
+    match &deltas[..] {
+        [Delta::Copy(copy)] if copy.src_byte_index == 0 && copy.bytes == sig.get_file_size() => {
+            Vec::new()
+        }
+        _ => deltas,
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::apply_delta;
+    use crate::tests::pseudo_random;
+
+    #[test]
+    fn blocks_cover_input_within_bounds() {
+        let chunking = ContentDefinedChunking::new(1_000);
+        assert_eq!(chunking.avg_size(), 1_024);
+        let buf = pseudo_random(100_000);
+        let lens = chunking.block_lens(&buf);
+        assert_eq!(lens.iter().sum::<usize>(), buf.len());
+        for len in &lens[..lens.len() - 1] {
+            assert!(*len >= chunking.min_size() && *len <= chunking.max_size());
+        }
+        // roughly the average size, not stuck at the bounds
+        assert!(lens.len() > 100_000 / chunking.max_size());
+        assert!(lens.len() < 100_000 / chunking.min_size());
+    }
+
+    #[test]
+    fn boundaries_follow_content() {
+        let chunking = ContentDefinedChunking::new(256);
+        let buf = pseudo_random(20_000);
+        let mut shifted = vec![b'x'];
+        shifted.extend_from_slice(&buf);
+
+        let offsets = |lens: Vec<usize>| -> Vec<usize> {
+            lens.iter()
+                .scan(0, |offset, len| {
+                    *offset += len;
+                    Some(*offset)
+                })
+                .collect()
+        };
+        let original = offsets(chunking.block_lens(&buf));
+        let moved = offsets(chunking.block_lens(&shifted));
+        // after the first few blocks every boundary is found again one byte later
+        let resynced = original
+            .iter()
+            .filter(|offset| moved.contains(&(**offset + 1)))
+            .count();
+        assert!(resynced + 3 >= original.len());
+    }
+
+    #[test]
+    fn inserting_at_the_front_changes_few_blocks() {
+        let chunking = ContentDefinedChunking::new(1_024);
+        let old = pseudo_random(64 * 1_024);
+        let mut new = vec![b'x'];
+        new.extend_from_slice(&old);
+
+        let diffs = check_diffs_chunked(chunking, Cursor::new(&old), Cursor::new(&new));
+        let added: u64 = diffs
+            .iter()
+            .map(|delta| match delta {
+                Delta::Add(add) => add.bytes,
+                Delta::Delete(_) | Delta::Copy(_) => 0,
+            })
+            .sum();
+        // only the blocks before the first boundary found again differ
+        assert!(diffs.len() <= 5);
+        assert!(added <= 3 * chunking.max_size() as u64);
+        assert_eq!(apply_delta(&old, 0, &diffs), new);
+    }
+
+    #[test]
+    fn chunked_diffs_round_trip() {
+        let chunking = ContentDefinedChunking::new(256);
+        let old = pseudo_random(20_000);
+        let mut new = old[..5_000].to_vec();
+        new.extend_from_slice(b"an edit in the middle");
+        new.extend_from_slice(&old[9_000..]);
+        let diffs = check_diffs_chunked(chunking, Cursor::new(&old), Cursor::new(&new));
+        assert_eq!(apply_delta(&old, 0, &diffs), new);
+
+        let diffs = check_diffs_chunked(chunking, Cursor::new(&old), Cursor::new(&[]));
+        assert_eq!(apply_delta(&old, 0, &diffs), Vec::<u8>::new());
+        let diffs = check_diffs_chunked(chunking, Cursor::new(&[]), Cursor::new(&new));
+        assert_eq!(apply_delta(&[], 0, &diffs), new);
+    }
+
+    #[test]
+    fn normalized_lines_ignore_trailing_spaces() {
+        fn trim_end(line: &[u8]) -> Vec<u8> {
+            let end = line
+                .iter()
+                .rposition(|byte| !b" \t\r\n".contains(byte))
+                .map_or(0, |last| last + 1);
+            line[..end].to_vec()
+        }
+        let old = b"Expecto patronum  \nLumos\r\nNox\n";
+        let new = b"Expecto patronum\nLumos \nNox\t\n";
+        assert!(!diff_lines(old, new).is_empty());
+        assert!(diff_lines_normalized(old, new, trim_end).is_empty());
+        assert!(diff_lines(old, old).is_empty());
+
+        // added lines keep their spaces and are placed in the rebuilt file
+        let new = b"Expecto patronum\nRiddikulus  \nNox\t\n";
+        let diffs = diff_lines_normalized(old, new, trim_end);
+        assert_eq!(
+            apply_delta(old, 0, &diffs),
+            b"Expecto patronum  \nRiddikulus  \nNox\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn line_diffs_add_and_delete_whole_lines() {
+        let old = b"Expecto patronum\nLumos\nNox\nAlohomora\n";
+        let new = b"Expecto patronum\nNox\nRiddikulus\nAlohomora";
+        let diffs = diff_lines(old, new);
+        let changes: Vec<&Delta> = diffs
+            .iter()
+            .filter(|delta| !matches!(delta, Delta::Copy(_)))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                &Delta::Delete(Delete {
+                    byte_index: 17,
+                    bytes: 6,
+                }),
+                &Delta::Add(Add {
+                    byte_index: 21,
+                    bytes: 20,
+                    content: b"Riddikulus\nAlohomora".to_vec(),
+                }),
+                &Delta::Delete(Delete {
+                    byte_index: 27,
+                    bytes: 10,
+                }),
+            ]
+        );
+        assert_eq!(apply_delta(old, 0, &diffs), new.to_vec());
+    }
+}
@@ -0,0 +1,360 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+use crate::rollsum::{RollingHash, Rollsum};
+use crate::{Add, Blake2b, DecodeError, Delta, DiffError, DiffIter, Digest, Signature};
+
+/// A diff against a signature fed the new file as it grows, e.g. a log being written
+///
+/// Every `push` hands out the deltas of the bytes pushed so far that no later byte can
+/// change, `finish` the rest once the new file is complete. Together they are the deltas
+/// `diff_iter` finds for the whole new file, neither coalesced. Bytes are kept only until
+/// the window passed them.
+pub struct Differ<D: Digest = Blake2b, R: RollingHash = Rollsum> {
+    sig: Option<Signature<D, R>>,
+    iter: Option<DiffIter<D, R, Pushed>>,
+    /// pushed bytes the window has not read yet
+    pushed: Rc<RefCell<VecDeque<u8>>>,
+    /// bytes pushed in total
+    len: u64,
+}
+
+impl<D: Digest, R: RollingHash> Differ<D, R> {
+    /// Starts diffing an empty new file against `sig`
+    pub fn new(sig: Signature<D, R>) -> Self {
+        Self {
+            sig: Some(sig),
+            iter: None,
+            pushed: Rc::new(RefCell::new(VecDeque::new())),
+            len: 0,
+        }
+    }
+
+    /// Appends `bytes` to the new file, returning the deltas settled by them
+    ///
+    /// The window only moves while a whole block past it was pushed, so the deltas of
+    /// the last two blocks or so wait for the next `push` or `finish`.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<Delta>, DiffError> {
+        self.pushed.borrow_mut().extend(bytes);
+        self.len += bytes.len() as u64;
+        let mut deltas = Vec::new();
+        let block_size = self.sig.as_ref().map_or(0, |sig| sig.get_block_size()) as u64;
+        if self.iter.is_none() && self.len > block_size {
+            let mut iter = self.start();
+            // the end of the file is not known yet, nothing may be taken as the last block
+            iter.new_len = u64::MAX;
+            self.iter = Some(iter);
+        }
+        let iter = match &mut self.iter {
+            Some(iter) => iter,
+            None => return Ok(deltas),
+        };
+        loop {
+            if let Some(delta) = iter.pending.pop_front() {
+                deltas.push(delta);
+                continue;
+            }
+            if let Some(error) = iter.error.take() {
+                return Err(error);
+            }
+            // a step reads at most a block past the window
+            let readable = iter.end_win + (iter.block_size as u64) < self.len;
+            if iter.finished || iter.unmatchable || !readable {
+                return Ok(deltas);
+            }
+            iter.step();
+        }
+    }
+
+    /// Ends the new file, returning the deltas of its bytes not handed out yet
+    pub fn finish(mut self) -> Result<Vec<Delta>, DiffError> {
+        let mut iter = match self.iter.take() {
+            Some(iter) => iter,
+            None => self.start(),
+        };
+        iter.new_len = self.len;
+        iter.collect()
+    }
+
+    /// The state of the diff, to carry on with `restore` later, e.g. after a dropped connection
+    ///
+    /// Holds the window, the new bytes not handed out yet and the pushed bytes the window
+    /// has not read yet, so it is a couple of blocks long plus whatever was pushed ahead.
+    /// The signature is not part of it. All numbers are big endian.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.push(self.iter.is_some() as u8);
+        out.extend_from_slice(&self.len.to_be_bytes());
+        let mut unread: Vec<u8> = Vec::new();
+        if let Some(iter) = &self.iter {
+            out.extend_from_slice(&iter.start_win.to_be_bytes());
+            out.extend_from_slice(&iter.end_win.to_be_bytes());
+            out.extend_from_slice(&iter.consumed_block_index.to_be_bytes());
+            out.push(iter.finished as u8);
+            out.push(iter.unmatchable as u8);
+            out.extend_from_slice(&iter.new_bytes.byte_index.to_be_bytes());
+            out.extend_from_slice(&iter.new_bytes.bytes.to_be_bytes());
+            put_bytes(&mut out, &iter.new_bytes.content);
+            let window: Vec<u8> = iter.window.iter().cloned().collect();
+            put_bytes(&mut out, &window);
+            // read ahead by the buffer but not taken by the window yet
+            unread.extend_from_slice(iter.new_buf.buffer());
+        }
+        unread.extend(self.pushed.borrow().iter());
+        put_bytes(&mut out, &unread);
+        out
+    }
+
+    /// Carries on the diff `snapshot` was taken of against the same signature
+    ///
+    /// The window and its rolling checksum are rebuilt as they were, pushing the rest of the
+    /// new file then hands out the deltas the interrupted `Differ` would have.
+    pub fn restore(sig: Signature<D, R>, snapshot: &[u8]) -> Result<Self, DecodeError> {
+        let mut snapshot = Snapshot { bytes: snapshot };
+        let started = snapshot.u8()? != 0;
+        let len = snapshot.u64()?;
+        let mut differ = Self {
+            sig: None,
+            iter: None,
+            pushed: Rc::new(RefCell::new(VecDeque::new())),
+            len,
+        };
+        if !started {
+            differ.sig = Some(sig);
+        } else {
+            let block_size = sig.get_block_size();
+            // nothing is pushed yet, so nothing is read before the state is overwritten
+            let mut iter = DiffIter::with_signature(sig, differ.pushed_from(0));
+            let start_win = snapshot.u64()?;
+            let end_win = snapshot.u64()?;
+            let consumed_block_index = snapshot.u64()? as i64;
+            let finished = snapshot.u8()? != 0;
+            let unmatchable = snapshot.u8()? != 0;
+            let mut new_bytes = Add::new(snapshot.u64()?);
+            new_bytes.bytes = snapshot.u64()?;
+            new_bytes.content = snapshot.bytes()?.to_vec();
+            let window = snapshot.bytes()?;
+            // an unmatchable diff never fills its window
+            if window.len() != block_size && !unmatchable {
+                return Err(DecodeError::InvalidWindow);
+            }
+            // a signature the diff cannot start with fails the same way again
+            if iter.error.is_none() {
+                iter.rs = R::new(&iter.sig.seeded(window));
+                iter.window = window.iter().cloned().collect();
+                iter.start_win = start_win;
+                iter.end_win = end_win;
+                iter.consumed_block_index = consumed_block_index;
+                iter.finished = finished;
+                iter.unmatchable = unmatchable;
+                iter.new_bytes = new_bytes;
+                iter.new_len = u64::MAX;
+            }
+            differ.iter = Some(iter);
+        }
+        differ.pushed.borrow_mut().extend(snapshot.bytes()?);
+        if !snapshot.bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(differ)
+    }
+
+    fn start(&mut self) -> DiffIter<D, R, Pushed> {
+        let new_buf = self.pushed_from(self.len);
+        // only taken once, `push` and `finish` keep the iterator from then on
+        DiffIter::with_signature(self.sig.take().unwrap(), new_buf)
+    }
+
+    fn pushed_from(&self, len: u64) -> Pushed {
+        Pushed {
+            bytes: Rc::clone(&self.pushed),
+            read: 0,
+            len,
+        }
+    }
+}
+
+/// Appends `bytes` after their length
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// The part of a snapshot not read yet
+struct Snapshot<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Snapshot<'a> {
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        let mut be = [0u8; 8];
+        be.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(be))
+    }
+
+    /// Bytes written by `put_bytes`
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.u64()?;
+        self.take(len.min(usize::MAX as u64) as usize)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+}
+
+/// The new file as far as it was pushed, read bytes are dropped
+struct Pushed {
+    bytes: Rc<RefCell<VecDeque<u8>>>,
+    read: u64,
+    /// bytes pushed when the diff started, the end sought to
+    len: u64,
+}
+
+impl Read for Pushed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.bytes.borrow_mut().read(buf)?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Pushed {
+    /// Only the start and end are sought to before anything is read
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Start(0) if self.read == 0 => Ok(0),
+            SeekFrom::End(0) if self.read == 0 => Ok(self.len),
+            SeekFrom::Current(0) => Ok(self.read),
+            _ => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "pushed bytes cannot be sought",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::pseudo_random;
+    use crate::{apply_delta, check_diffs, coalesce_deltas, diff_iter};
+    use std::io::Cursor;
+
+    fn pushed_in(old: &[u8], new: &[u8], block_size: usize, parts: &[usize]) -> Vec<Delta> {
+        let sig = Signature::from_slice(old, block_size).unwrap();
+        let mut differ = Differ::new(sig);
+        let mut deltas = Vec::new();
+        let mut start = 0;
+        for &end in parts {
+            deltas.extend(differ.push(&new[start..end]).unwrap());
+            start = end;
+        }
+        deltas.extend(differ.push(&new[start..]).unwrap());
+        deltas.extend(differ.finish().unwrap());
+        deltas
+    }
+
+    #[test]
+    fn pushed_halves_diff_like_the_whole_file() {
+        let old = pseudo_random(16_384);
+        let mut new = old.clone();
+        new.splice(12_000..12_010, b"Lumos".iter().cloned());
+        new.splice(4_096..4_096, b"not ".iter().cloned());
+        new.extend_from_slice(b"Mischief managed");
+
+        let whole: Vec<Delta> = diff_iter(64, Cursor::new(&old), Cursor::new(&new))
+            .map(Result::unwrap)
+            .collect();
+        let halves = pushed_in(&old, &new, 64, &[new.len() / 2]);
+        assert_eq!(halves, whole);
+        assert_eq!(
+            coalesce_deltas(halves),
+            check_diffs(64, Cursor::new(&old), Cursor::new(&new)).unwrap()
+        );
+        // parts shorter than a block, ending right at the end of the file
+        let bytes: Vec<usize> = (1..new.len()).step_by(37).collect();
+        let small = pushed_in(&old, &new, 64, &bytes);
+        assert_eq!(small, whole);
+        assert_eq!(apply_delta(&old, 64, &small), new);
+    }
+
+    #[test]
+    fn restored_snapshots_diff_like_an_uninterrupted_run() {
+        let old = pseudo_random(16_384);
+        let mut new = old.clone();
+        new.splice(9_000..9_003, b"Nox".iter().cloned());
+        new.splice(2_000..2_000, b"Alohomora".iter().cloned());
+        let whole = pushed_in(&old, &new, 64, &[]);
+
+        // before the window started, mid file and with a partial block pushed ahead
+        for &cut in &[10, 5_000, 9_030, new.len() - 10] {
+            let sig = Signature::from_slice(&old, 64).unwrap();
+            let mut differ = Differ::new(sig);
+            let mut deltas = differ.push(&new[..cut]).unwrap();
+            let snapshot = differ.snapshot();
+            drop(differ);
+
+            let sig = Signature::from_slice(&old, 64).unwrap();
+            let mut restored = Differ::restore(sig, &snapshot).unwrap();
+            assert_eq!(restored.snapshot(), snapshot);
+            deltas.extend(restored.push(&new[cut..]).unwrap());
+            deltas.extend(restored.finish().unwrap());
+            assert_eq!(deltas, whole);
+        }
+
+        let sig = Signature::from_slice(&old, 64).unwrap();
+        let mut differ = Differ::new(sig);
+        differ.push(&new[..5_000]).unwrap();
+        let snapshot = differ.snapshot();
+        let restore = |snapshot: &[u8]| {
+            Differ::restore(Signature::from_slice(&old, 64).unwrap(), snapshot).err()
+        };
+        assert_eq!(
+            restore(&snapshot[..snapshot.len() - 1]),
+            Some(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            restore(&[&snapshot[..], &[0]].concat()),
+            Some(DecodeError::TrailingBytes)
+        );
+        let other_block_size = Signature::from_slice(&old, 32).unwrap();
+        assert_eq!(
+            Differ::restore(other_block_size, &snapshot).err(),
+            Some(DecodeError::InvalidWindow)
+        );
+    }
+
+    #[test]
+    fn deltas_are_handed_out_as_the_file_grows() {
+        let old = pseudo_random(4_096);
+        let sig = Signature::from_slice(&old, 64).unwrap();
+        let mut differ = Differ::new(sig);
+        assert_eq!(differ.push(&old[..64]).unwrap(), vec![]);
+        let first = differ.push(&old[64..2_048]).unwrap();
+        assert!(!first.is_empty());
+        assert!(first.iter().all(|delta| matches!(delta, Delta::Copy(_))));
+        // the old file ended early, its later blocks are deleted once the new one ends
+        let rest = differ.finish().unwrap();
+        assert!(matches!(rest.last(), Some(Delta::Delete(_))));
+
+        let empty = Differ::new(Signature::from_slice(&old, 64).unwrap());
+        assert_eq!(
+            empty.finish().unwrap(),
+            diff_iter(64, Cursor::new(&old), Cursor::new(&[][..]))
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        );
+    }
+}
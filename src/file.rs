@@ -1,18 +1,63 @@
 use std::fs::File;
+use std::io::{self, Cursor, ErrorKind, Read};
 use std::path::Path;
 
-pub fn read_file(filepath: &'static str) -> Vec<u8> {
-    let path = Path::new(filepath);
+use crate::{check_diffs, Delta};
+
+/// Reads the whole file at `filepath` into memory
+pub fn read_file<P: AsRef<Path>>(filepath: P) -> io::Result<Vec<u8>> {
     let mut buf = Vec::new();
-    let mut file = match File::open(&path) {
-        Err(_) => panic!("couldn't open {}"),
-        Ok(file) => file,
-    };
-    match file.read_to_end(&mut buf) {
-        Ok(bytes) => {
-            println!("file size in bytes: {}", bytes);
-            buf
-        }
-        Err(_) => panic!("Cannot read file at {}", filepath),
+    let mut file = File::open(filepath)?;
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Diffs both files, a `DiffError` is reported as `InvalidInput`
+///
+/// Only the old file is read into memory, the new one is streamed.
+pub fn diff_files<P: AsRef<Path>>(old: P, new: P, block_size: usize) -> io::Result<Vec<Delta>> {
+    let old = read_file(old)?;
+    let new = File::open(new)?;
+    check_diffs(block_size, Cursor::new(&old), new)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn read_file_returns_content() {
+        let path = env::temp_dir().join("rolling_hash_read_file_returns_content");
+        fs::write(&path, "I solemnly swear that I am up to no good").unwrap();
+        let content = read_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            content.unwrap(),
+            b"I solemnly swear that I am up to no good".to_vec()
+        );
+    }
+
+    #[test]
+    fn read_file_fails_on_missing_path() {
+        let path = env::temp_dir().join("rolling_hash_read_file_fails_on_missing_path");
+        let err = read_file(path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn file_backed_new_file_diffs_like_memory() {
+        let old = crate::tests::pseudo_random(20_000);
+        let mut new = old.clone();
+        new.splice(9_000..9_100, b"I must not tell lies".iter().cloned());
+        let path = env::temp_dir().join("rolling_hash_file_backed_new_file_diffs_like_memory");
+        fs::write(&path, &new).unwrap();
+
+        let from_file = check_diffs(16, Cursor::new(&old), File::open(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        let from_memory = check_diffs(16, Cursor::new(&old), Cursor::new(&new));
+        assert_eq!(from_file.unwrap(), from_memory.unwrap());
     }
 }
@@ -1,259 +1,3031 @@
-#![allow(unused_variables)]
-pub use blake2::{Blake2b, Digest};
-use std::io::{Cursor, Read};
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
 
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+pub use blake2::{Blake2b, Blake2s, Digest};
+use core::cmp::Ordering;
+use core::fmt;
+#[cfg(feature = "std")]
+use core::marker::PhantomData;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::borrow::Borrow;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom};
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+#[cfg(feature = "std")]
+mod builder;
+mod chunking;
+mod crc32;
+#[cfg(feature = "std")]
+mod differ;
+#[cfg(feature = "std")]
+mod file;
+#[cfg(feature = "std")]
+mod merge;
+#[cfg(feature = "std")]
+mod multilevel;
+mod rabin_karp;
 mod rollsum;
 mod signature;
+#[cfg(feature = "std")]
+mod stream;
+
+#[cfg(feature = "std")]
+pub use builder::{DiffBuilder, MatchPolicy, PROGRESS_INTERVAL};
+pub use chunking::ContentDefinedChunking;
+#[cfg(feature = "std")]
+pub use chunking::{check_diffs_chunked, diff_lines, diff_lines_normalized};
+pub use crc32::Crc32;
+#[cfg(feature = "std")]
+pub use differ::Differ;
+#[cfg(feature = "std")]
+pub use file::{diff_files, read_file};
+#[cfg(feature = "std")]
+pub use merge::{three_way, MergeResult};
+#[cfg(feature = "std")]
+pub use multilevel::{check_diffs_regions, diff_multilevel};
+pub use rabin_karp::RabinKarp;
+pub use rollsum::{Error as RollsumError, RollingHash, Rollsum, RollsumBuilder, ADLER_MODULUS};
+pub use signature::{
+    BlockChange, BlockHash, CollisionStats, DecodeError, Normalizer, Signature, StrongHash,
+    MAX_DECODED_BLOCK_SIZE, MAX_STRONG_HASH_LEN,
+};
+#[cfg(feature = "std")]
+pub use stream::{
+    read_delta, read_verified_delta, write_delta, write_signature_delta, write_verified_delta,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delta {
+    Add(Add),
+    Delete(Delete),
+    Copy(Copy),
+}
+
+/// New bytes inserted into the rebuilt file
+#[derive(PartialEq, Eq)]
+pub struct Add {
+    /// The first byte index of the content in the new file
+    byte_index: u64,
+    /// Total bytes to be inserted
+    bytes: u64,
+    /// Content to be inserted
+    content: Vec<u8>,
+}
+
+/// Totals of a diff, e.g. to tune the block size
+#[derive(Debug, Default, PartialEq)]
+pub struct DiffStats {
+    /// Bytes of the new file sent as content of `Add`s
+    pub literal_bytes: u64,
+    /// Bytes of the new file copied from the old file
+    pub matched_bytes: u64,
+    pub deltas: usize,
+}
+
+impl DiffStats {
+    /// Totals of `deltas`, those without `Copy`s count no matched bytes
+    pub fn new(deltas: &[Delta]) -> Self {
+        let mut stats = DiffStats {
+            deltas: deltas.len(),
+            ..DiffStats::default()
+        };
+        for delta in deltas {
+            match delta {
+                Delta::Add(add) => stats.literal_bytes += add.bytes,
+                Delta::Copy(copy) => stats.matched_bytes += copy.bytes,
+                Delta::Delete(_) => {}
+            }
+        }
+        stats
+    }
+
+    /// Share of the new file sent as literal bytes, 0 for an empty new file
+    pub fn literal_ratio(&self) -> f64 {
+        match self.literal_bytes + self.matched_bytes {
+            0 => 0.0,
+            total => self.literal_bytes as f64 / total as f64,
+        }
+    }
+}
+
+/// A delta whose added content is borrowed from the new file, see `check_diffs_borrowed`
+#[derive(Debug)]
+pub enum DeltaRef<'a> {
+    Add(AddRef<'a>),
+    Delete(Delete),
+    Copy(Copy),
+}
+
+/// An `Add` of bytes of the new file that outlives it
+#[derive(Debug, PartialEq)]
+pub struct AddRef<'a> {
+    /// The first byte index to insert the content
+    byte_index: u64,
+    /// Content to be inserted
+    content: &'a [u8],
+}
+
+/// A range of the old file missing from the new one
+///
+/// Unlike an `Add` it is in coordinates of the old file, like a `Copy`, as the deleted
+/// bytes are nowhere in the new file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Delete {
+    /// The first byte index in the old file to delete
+    byte_index: u64,
+    /// Total bytes to be deleted
+    bytes: u64,
+}
+
+/// A matched range of the old file, taken over as is
+#[derive(Debug, PartialEq, Eq)]
+pub struct Copy {
+    /// The first byte index in the old file to copy from
+    src_byte_index: u64,
+    /// Total bytes to be copied
+    bytes: u64,
+    /// The first matched block of the old file's signature
+    ///
+    /// With `DiffBuilder::skip_prefix` and `check_diffs_regions` blocks count from the start
+    /// of the diffed range, `apply_delta` only goes by `src_byte_index`.
+    block_index: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DiffError {
+    /// The non empty old file is shorter than a single block
+    InputTooSmall { needed: usize, got: usize },
+    /// The deltas found are out of order, see `Delta::validate`
+    Overlap(OverlapError),
+    /// Blocks of fixed size must be at least a byte
+    InvalidBlockSize,
+    /// The signature was generated with other algorithms than the diff, see
+    /// `Signature::get_algorithm_id`
+    AlgorithmMismatch { expected: u32, got: u32 },
+    /// The cancel flag was set while diffing, see `DiffBuilder::diff_cancellable`
+    Cancelled,
+    /// The short last block of the signature is not known, see `Signature::append`
+    NotAppendable,
+    /// Reading or seeking a file failed, only the kind is kept so errors stay comparable
+    #[cfg(feature = "std")]
+    Io(ErrorKind),
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffError::InputTooSmall { needed, got } => write!(
+                f,
+                "input of {} bytes is shorter than a block of {} bytes",
+                got, needed
+            ),
+            DiffError::Overlap(err) => err.fmt(f),
+            DiffError::InvalidBlockSize => write!(f, "block size must be at least 1"),
+            DiffError::AlgorithmMismatch { expected, got } => write!(
+                f,
+                "signature algorithms {} do not match the diff algorithms {}",
+                got, expected
+            ),
+            DiffError::Cancelled => write!(f, "diff cancelled"),
+            DiffError::NotAppendable => write!(
+                f,
+                "only generated signatures of fixed size blocks can be appended to"
+            ),
+            #[cfg(feature = "std")]
+            DiffError::Io(kind) => write!(f, "reading a file failed: {}", kind),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DiffError {}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for DiffError {
+    fn from(err: io::Error) -> Self {
+        DiffError::Io(err.kind())
+    }
+}
+
+/// The delta at `index` starts before the end of an earlier one
+#[derive(Debug, PartialEq)]
+pub struct OverlapError {
+    pub index: usize,
+}
+
+impl fmt::Display for OverlapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "delta {} overlaps an earlier delta", self.index)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for OverlapError {}
+
+/// The rebuilt file does not hash to the hash of the new file
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    pub expected: Vec<u8>,
+    pub got: Vec<u8>,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rebuilt file does not match the hash of the new file")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VerifyError {}
+
+/// A block of the old file copied by the deltas does not hash as in the signature
+#[derive(Debug, PartialEq)]
+pub struct BlockMismatch {
+    pub block_index: u64,
+}
+
+impl fmt::Display for BlockMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block {} of the old file does not match its signature",
+            self.block_index
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BlockMismatch {}
+
+impl Delta {
+    /// First byte of the delta, in the new file for an `Add` and the old one otherwise
+    pub fn byte_index(&self) -> u64 {
+        match self {
+            Delta::Add(add) => add.byte_index,
+            Delta::Delete(delete) => delete.byte_index,
+            Delta::Copy(copy) => copy.src_byte_index,
+        }
+    }
+
+    /// Orders deltas of the same `byte_index`: `Add`, `Delete`, then `Copy`
+    fn rank(&self) -> u8 {
+        match self {
+            Delta::Add(_) => 0,
+            Delta::Delete(_) => 1,
+            Delta::Copy(_) => 2,
+        }
+    }
+
+    /// Bytes added, deleted or copied
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Delta::Add(add) => add.bytes,
+            Delta::Delete(delete) => delete.bytes,
+            Delta::Copy(copy) => copy.bytes,
+        }
+    }
+
+    /// Checks the deltas are in order and do not overlap
+    ///
+    /// `Add`s have to be in ascending order of the new file, each starting after the new
+    /// bytes added or copied before it. `Delete`s have to be in ascending order of the old
+    /// file, each starting after the previous one. The two run in different coordinates, so
+    /// a `Delete` may have a lower index than an `Add` before it.
+    pub fn validate(deltas: &[Delta]) -> Result<(), OverlapError> {
+        let mut new_end = 0;
+        let mut deleted_end = 0;
+        for (index, delta) in deltas.iter().enumerate() {
+            match delta {
+                Delta::Add(add) => {
+                    if add.byte_index < new_end {
+                        return Err(OverlapError { index });
+                    }
+                    new_end = add.byte_index + add.bytes;
+                }
+                Delta::Delete(delete) => {
+                    if delete.byte_index < deleted_end {
+                        return Err(OverlapError { index });
+                    }
+                    deleted_end = delete.byte_index + delete.bytes;
+                }
+                Delta::Copy(copy) => new_end += copy.bytes,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// By `byte_index`, ties broken by kind, length and content
+///
+/// Indexes of `Add`s and the others are of different files, sorting mixed deltas does not
+/// give the order they apply in. It is meant for comparing and deduplicating them.
+impl Ord for Delta {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.byte_index(), self.rank(), self.bytes())
+            .cmp(&(other.byte_index(), other.rank(), other.bytes()))
+            .then_with(|| match (self, other) {
+                (Delta::Add(add), Delta::Add(other)) => add.content.cmp(&other.content),
+                (Delta::Copy(copy), Delta::Copy(other)) => copy.block_index.cmp(&other.block_index),
+                _ => Ordering::Equal,
+            })
+    }
+}
+
+impl PartialOrd for Delta {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Content bytes shown by `Display` for an `Add`, longer content is cut
+const DISPLAY_CONTENT_LEN: usize = 32;
+
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Delta::Add(add) => add.fmt(f),
+            Delta::Delete(delete) => delete.fmt(f),
+            Delta::Copy(copy) => copy.fmt(f),
+        }
+    }
+}
+
+/// `+@40 4 bytes: "not "`, the content as lossy UTF-8
+impl fmt::Display for Add {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = self.content.len().min(DISPLAY_CONTENT_LEN);
+        write!(
+            f,
+            "+@{} {} bytes: {:?}",
+            self.byte_index,
+            self.bytes,
+            alloc::string::String::from_utf8_lossy(&self.content[..shown])
+        )?;
+        if shown < self.content.len() {
+            write!(f, "...")?;
+        }
+        Ok(())
+    }
+}
+
+/// `Add { byte_index: 40, bytes: 4, content: "not " }`, content that is not UTF-8 in hex
+impl fmt::Debug for Add {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Add")
+            .field("byte_index", &self.byte_index)
+            .field("bytes", &self.bytes)
+            .field("content", &DebugContent(&self.content))
+            .finish()
+    }
+}
+
+/// Content as a quoted string or hex bytes, cut after `DISPLAY_CONTENT_LEN` bytes
+struct DebugContent<'a>(&'a [u8]);
+
+impl fmt::Debug for DebugContent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.0[..self.0.len().min(DISPLAY_CONTENT_LEN)];
+        // the whole content has to be UTF-8 for a string, cut before a split character
+        match core::str::from_utf8(self.0) {
+            Ok(text) => {
+                let mut end = shown.len();
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                write!(f, "{:?}", &text[..end])?
+            }
+            Err(_) => {
+                for byte in shown {
+                    write!(f, "{:02x}", byte)?;
+                }
+            }
+        }
+        if shown.len() < self.0.len() {
+            write!(f, "... ({} bytes)", self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// `-@0 5 bytes`
+impl fmt::Display for Delete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "-@{} {} bytes", self.byte_index, self.bytes)
+    }
+}
+
+/// `=@0 40 bytes from block 0`
+impl fmt::Display for Copy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "=@{} {} bytes from block {}",
+            self.src_byte_index, self.bytes, self.block_index
+        )
+    }
+}
+
+/// Renders `deltas` like a unified diff, e.g. for logs
+///
+/// Every run of `Add`s and `Delete`s is a hunk headed by `@@ -old_start,old_len
+/// +new_start,new_len @@` in 0-based byte offsets, with up to `context` of the `Copy`s
+/// around it. Hunks whose context touches are joined. The content of an `Add` follows a
+/// `+` on one line, escaped like `escape_ascii`, while `Delete`s and `Copy`s are shown as
+/// by `Display`, the latter indented by a space.
+pub fn format_unified(deltas: &[Delta], context: usize) -> alloc::string::String {
+    use fmt::Write;
+
+    let spans = delta_spans(deltas);
+    let mut shown = alloc::vec![false; deltas.len()];
+    for (index, delta) in deltas.iter().enumerate() {
+        if !matches!(delta, Delta::Copy(_)) {
+            let end = deltas.len().min(index + context + 1);
+            shown[index.saturating_sub(context)..end].fill(true);
+        }
+    }
+
+    let mut out = alloc::string::String::new();
+    let mut index = 0;
+    while index < deltas.len() {
+        if !shown[index] {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < deltas.len() && shown[index] {
+            index += 1;
+        }
+        let hunk = &spans[start..index];
+        let old_start = hunk.iter().map(|(old, _)| old.start).min().unwrap_or(0);
+        let old_end = hunk.iter().map(|(old, _)| old.end).max().unwrap_or(0);
+        let new_start = hunk.iter().map(|(_, new)| new.start).min().unwrap_or(0);
+        let new_end = hunk.iter().map(|(_, new)| new.end).max().unwrap_or(0);
+        // writing to a String does not fail
+        let _ = writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            old_start,
+            old_end - old_start,
+            new_start,
+            new_end - new_start
+        );
+        for delta in &deltas[start..index] {
+            let _ = match delta {
+                Delta::Add(add) => writeln!(out, "+{}", add.content.escape_ascii()),
+                Delta::Delete(delete) => writeln!(out, "{}", delete),
+                Delta::Copy(copy) => writeln!(out, " {}", copy),
+            };
+        }
+    }
+    out
+}
+
+/// Bytes of the old file and of the new file every delta stands for, in order
+///
+/// Tracks the old file the way `apply_delta` does, so `Add`s are placed in the old file and
+/// `Delete`s in the new one as empty ranges.
+pub(crate) fn delta_spans(deltas: &[Delta]) -> Vec<(Range<u64>, Range<u64>)> {
+    let mut spans = Vec::with_capacity(deltas.len());
+    let mut old_index = 0;
+    let mut new_len = 0;
+    for delta in deltas {
+        spans.push(match delta {
+            Delta::Add(add) => {
+                let at = old_index + add.byte_index.saturating_sub(new_len);
+                new_len = add.byte_index + add.bytes;
+                old_index = at;
+                (at..at, add.byte_index..new_len)
+            }
+            Delta::Delete(delete) => {
+                let start = delete.byte_index.max(old_index);
+                new_len += start - old_index;
+                old_index = start + delete.bytes;
+                (start..old_index, new_len..new_len)
+            }
+            Delta::Copy(copy) => {
+                let old = copy.src_byte_index..copy.src_byte_index + copy.bytes;
+                let new = new_len..new_len + copy.bytes;
+                new_len += copy.bytes;
+                old_index = old_index.max(old.end);
+                (old, new)
+            }
+        });
+    }
+    spans
+}
+
+impl<'a> DeltaRef<'a> {
+    /// The same delta owning a copy of its content, e.g. to be serialized
+    pub fn into_owned(self) -> Delta {
+        match self {
+            DeltaRef::Add(add) => Delta::Add(Add {
+                byte_index: add.byte_index,
+                bytes: add.content.len() as u64,
+                content: add.content.to_vec(),
+            }),
+            DeltaRef::Delete(delete) => Delta::Delete(delete),
+            DeltaRef::Copy(copy) => Delta::Copy(copy),
+        }
+    }
+}
+
+impl<'a> AddRef<'a> {
+    pub fn byte_index(&self) -> u64 {
+        self.byte_index
+    }
+
+    pub fn content(&self) -> &'a [u8] {
+        self.content
+    }
+}
+
+impl Delete {
+    pub fn byte_index(&self) -> u64 {
+        self.byte_index
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Copy {
+    pub fn src_byte_index(&self) -> u64 {
+        self.src_byte_index
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The signature block the copy starts at, later blocks follow in order when coalesced
+    pub fn block_index(&self) -> u64 {
+        self.block_index
+    }
+}
+
+impl Add {
+    pub fn new(byte_index: u64) -> Self {
+        Self {
+            byte_index,
+            bytes: 0,
+            content: Vec::new(),
+        }
+    }
+
+    pub fn byte_index(&self) -> u64 {
+        self.byte_index
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Appends `byte` to the content
+    pub fn push_byte(&mut self, byte: u8) {
+        self.content.push(byte);
+        self.bytes += 1;
+    }
+
+    /// Appends `bytes` to the content
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.content.extend_from_slice(bytes);
+        self.bytes += bytes.len() as u64;
+    }
+
+    /// Appends `bytes`, only counted without `keep_content` as the content is borrowed later
+    #[cfg(feature = "std")]
+    fn take(&mut self, bytes: &[u8], keep_content: bool) {
+        if keep_content {
+            self.extend(bytes);
+        } else {
+            self.bytes += bytes.len() as u64;
+        }
+    }
+}
+
+/// Diffs the new file against blocks of `block_size` bytes of the old file
+///
+/// The new file is read front to back through a buffer, so any seekable reader like a
+/// `File` works without loading it into memory first.
+#[cfg(feature = "std")]
+pub fn check_diffs(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    new_buf: impl Read + Seek,
+) -> Result<Vec<Delta>, DiffError> {
+    DiffBuilder::new()
+        .block_size(block_size)
+        .diff(old_buf, new_buf)
+}
+
+/// Same as `check_diffs` for files already in memory
+#[cfg(feature = "std")]
+pub fn check_diffs_slice(
+    block_size: usize,
+    old: &[u8],
+    new: &[u8],
+) -> Result<Vec<Delta>, DiffError> {
+    check_diffs(block_size, Cursor::new(old), Cursor::new(new))
+}
+
+/// Same as `check_diffs` with the content of `Add`s borrowed from `new_buf` instead of copied
+#[cfg(feature = "std")]
+pub fn check_diffs_borrowed<'a>(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    new_buf: &'a [u8],
+) -> Result<Vec<DeltaRef<'a>>, DiffError> {
+    DiffBuilder::new()
+        .block_size(block_size)
+        .diff_borrowed(old_buf, new_buf)
+}
+
+/// Same as `check_diffs` along with the totals of the deltas
+///
+/// The deltas are not coalesced, so every matched block is its own `Copy` and a new file
+/// identical to the old one still counts all its bytes as matched.
+#[cfg(feature = "std")]
+pub fn diff_with_stats(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    new_buf: impl Read + Seek,
+) -> Result<(Vec<Delta>, DiffStats), DiffError> {
+    let deltas = DiffBuilder::new()
+        .block_size(block_size)
+        .coalesce(false)
+        .diff(old_buf, new_buf)?;
+    let stats = DiffStats::new(&deltas);
+    Ok((deltas, stats))
+}
+
+/// Share of `new` that a diff against `old` copies instead of sending, 0 for an empty `new`
+///
+/// Close to 1 the diff is worth it, close to 0 sending the whole new file is about as small.
+#[cfg(feature = "std")]
+pub fn estimate_savings(old: &[u8], new: &[u8], block_size: usize) -> Result<f64, DiffError> {
+    let (_, stats) = diff_with_stats(block_size, Cursor::new(old), Cursor::new(new))?;
+    Ok(match new.len() {
+        0 => 0.0,
+        new_len => stats.matched_bytes as f64 / new_len as f64,
+    })
+}
+
+/// Same as `check_diffs` with the block size picked from the old file length
+#[cfg(feature = "std")]
+pub fn check_diffs_auto(
+    old_buf: Cursor<&[u8]>,
+    new_buf: impl Read + Seek,
+) -> Result<Vec<Delta>, DiffError> {
+    DiffBuilder::new().diff(old_buf, new_buf)
+}
+
+/// Block size for a file of `file_len` bytes, like rsync this is about sqrt(file_len)
+///
+/// The square root is rounded down to a power of two and clamped between 16 and 4096.
+pub fn recommended_block_size(file_len: usize) -> usize {
+    let root = file_len.isqrt().max(1);
+    let power_of_two = 1 << (usize::BITS - 1 - root.leading_zeros());
+    power_of_two.clamp(16, 4096)
+}
+
+/// Same as `check_diffs` but blocks are compared with the strong hash `D`
+#[cfg(feature = "std")]
+pub fn check_diffs_with_digest<D: Digest>(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    new_buf: impl Read + Seek,
+) -> Result<Vec<Delta>, DiffError> {
+    check_diffs_with::<D, Rollsum>(block_size, old_buf, new_buf)
+}
+
+/// Same as `check_diffs` with the strong hash `D` and the weak rolling checksum `R`
+#[cfg(feature = "std")]
+pub fn check_diffs_with<D: Digest, R: RollingHash>(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    new_buf: impl Read + Seek,
+) -> Result<Vec<Delta>, DiffError> {
+    DiffBuilder::<D, R>::with_digest()
+        .block_size(block_size)
+        .diff(old_buf, new_buf)
+}
+
+/// Same as `check_diffs` against a signature of the old file, e.g. one decoded from `encode`
+///
+/// The old file is never read, as in rsync the new file's side only needs the signature.
+/// Signatures cut by content fail with `DiffError::InvalidBlockSize`.
+#[cfg(feature = "std")]
+pub fn diff_against_signature(
+    sig: &Signature,
+    new_buf: impl Read + Seek,
+) -> Result<Vec<Delta>, DiffError> {
+    DiffBuilder::new().diff_against_signature(sig, new_buf)
+}
+
+/// Lazily yields the deltas of `check_diffs`, one at a time
+///
+/// Unlike `check_diffs` adjacent deltas are handed out as found and not coalesced.
+#[cfg(feature = "std")]
+pub fn diff_iter<'a>(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    new_buf: impl Read + Seek + 'a,
+) -> impl Iterator<Item = Result<Delta, DiffError>> + 'a {
+    DiffIter::<Blake2b, Rollsum, _>::new(block_size, Blake2b::output_size(), 0, old_buf, new_buf)
+}
+
+/// Merges consecutive `Add`s of contiguous content and consecutive `Delete`s or `Copy`s of
+/// contiguous ranges
+pub fn coalesce_deltas(deltas: Vec<Delta>) -> Vec<Delta> {
+    coalesce_deltas_capped(deltas, u64::MAX)
+}
+
+/// Same as `coalesce_deltas` without merging `Add`s past `max_literal_run` bytes
+pub(crate) fn coalesce_deltas_capped(deltas: Vec<Delta>, max_literal_run: u64) -> Vec<Delta> {
+    let mut coalesced: Vec<Delta> = Vec::with_capacity(deltas.len());
+    for delta in deltas {
+        match (coalesced.last_mut(), delta) {
+            (Some(Delta::Add(last)), Delta::Add(add))
+                if last.byte_index + last.bytes == add.byte_index
+                    && last.bytes + add.bytes <= max_literal_run =>
+            {
+                last.bytes += add.bytes;
+                last.content.extend(add.content);
+            }
+            (Some(Delta::Delete(last)), Delta::Delete(delete))
+                if last.byte_index + last.bytes == delete.byte_index =>
+            {
+                last.bytes += delete.bytes;
+            }
+            (Some(Delta::Copy(last)), Delta::Copy(copy))
+                if last.src_byte_index + last.bytes == copy.src_byte_index =>
+            {
+                last.bytes += copy.bytes;
+            }
+            (_, delta) => coalesced.push(delta),
+        }
+    }
+    coalesced
+}
+
+/// The sliding window state machine, every step moves the window by a byte or a block
+#[cfg(feature = "std")]
+struct DiffIter<D: Digest, R: RollingHash, N: Read, S = Signature<D, R>> {
+    block_size: usize,
+    // read sequentially, the window only ever moves forward
+    new_buf: BufReader<N>,
+    new_len: u64,
+    sig: S,
+    // slideing window through new file, a ring buffer so rolling a byte is O(1)
+    window: VecDeque<u8>,
+    rs: R,
+    start_win: u64,
+    end_win: u64,
+    // new bytes not yet handed out as a delta
+    new_bytes: Add,
+    // the last block consumed of the Signature file, start before block zero
+    consumed_block_index: i64,
+    // blocks up to the consumed one may be matched again
+    reuse_blocks: bool,
+    // which block is taken when several hash the same
+    policy: MatchPolicy,
+    // deltas found by the last step but not yet handed out
+    pending: VecDeque<Delta>,
+    error: Option<DiffError>,
+    finished: bool,
+    // no window of the new file can match
+    unmatchable: bool,
+    // added bytes are only counted if unset
+    keep_content: bool,
+    // longest `Add` handed out
+    max_literal_run: u64,
+    digest: PhantomData<(D, R)>,
+}
+
+#[cfg(feature = "std")]
+impl<D: Digest, R: RollingHash, N: Read + Seek> DiffIter<D, R, N> {
+    fn new(
+        block_size: usize,
+        strong_hash_len: usize,
+        seed: u64,
+        mut old_buf: Cursor<&[u8]>,
+        new_buf: N,
+    ) -> Self {
+        let mut sig = Signature::<D, R>::with_digest(block_size)
+            .with_strong_hash_len(strong_hash_len)
+            .with_seed(seed);
+        sig.reserve_blocks(signature::expected_blocks(
+            old_buf.get_ref().len(),
+            block_size,
+        ));
+        // the only failure is a block size of 0, which `with_signature` reports as well
+        let _ = sig.generate(&mut old_buf);
+        Self::with_signature(sig, new_buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: Digest, R: RollingHash, N: Read + Seek, S: Borrow<Signature<D, R>>> DiffIter<D, R, N, S> {
+    /// Diffs against the signature of the old file, the old file itself is not needed
+    fn with_signature(sig: S, mut new_buf: N) -> Self {
+        let block_size = sig.borrow().get_block_size();
+        let old_len = sig.borrow().get_file_size() as usize;
+        let sought = new_buf
+            .seek(SeekFrom::End(0))
+            .and_then(|len| new_buf.seek(SeekFrom::Start(0)).map(|_| len));
+        let new_len = *sought.as_ref().unwrap_or(&0) as usize;
+        let mut iter = Self {
+            block_size,
+            sig,
+            // allocated once both files are known to hold a block
+            window: VecDeque::new(),
+            rs: R::new(&[]),
+            start_win: 0,
+            end_win: (block_size as u64).saturating_sub(1),
+            new_bytes: Add::new(0),
+            consumed_block_index: -1,
+            reuse_blocks: false,
+            policy: MatchPolicy::Smallest,
+            pending: VecDeque::new(),
+            error: None,
+            finished: false,
+            unmatchable: false,
+            keep_content: true,
+            max_literal_run: u64::MAX,
+            new_buf: BufReader::new(new_buf),
+            new_len: new_len as u64,
+            digest: PhantomData,
+        };
+
+        if let Err(err) = sought {
+            iter.error = Some(err.into());
+            iter.finished = true;
+            return iter;
+        }
+        let expected = signature::algorithm_id::<D, R>();
+        let got = iter.sig.borrow().get_algorithm_id();
+        if got != expected {
+            iter.error = Some(DiffError::AlgorithmMismatch { expected, got });
+            iter.finished = true;
+            return iter;
+        }
+        // signatures cut by content have no fixed block size either
+        if block_size == 0 {
+            iter.error = Some(DiffError::InvalidBlockSize);
+            iter.finished = true;
+            return iter;
+        }
+        // nothing to match against, the whole file is either new or removed
+        // a new file shorter than a block cannot hold a single matching window either
+        // the first step then adds all of it and deletes all the old blocks
+        if old_len == 0 || new_len < block_size {
+            iter.unmatchable = true;
+            return iter;
+        }
+        if old_len < block_size {
+            iter.error = Some(DiffError::InputTooSmall {
+                needed: block_size,
+                got: old_len,
+            });
+            iter.finished = true;
+            return iter;
+        }
+
+        // initial window and its weak hash
+        iter.window = VecDeque::from(vec![0u8; block_size]);
+        let filled = match fill_block(iter.new_buf.get_mut(), iter.window.make_contiguous()) {
+            Ok(filled) => filled,
+            Err(err) => {
+                iter.error = Some(err.into());
+                iter.finished = true;
+                return iter;
+            }
+        };
+        // the reader ended before its first block, what it had is new like a short file
+        if filled < block_size {
+            iter.window.truncate(filled);
+            iter.new_bytes
+                .take(iter.window.make_contiguous(), iter.keep_content);
+            iter.unmatchable = true;
+            return iter;
+        }
+        iter.rs = R::new(&iter.sig.borrow().seeded(iter.window.make_contiguous()));
+        iter
+    }
+
+    /// One iteration through the file, matched or not
+    ///
+    /// A failed read ends the diff, the error is handed out after the pending deltas.
+    fn step(&mut self) {
+        if let Err(err) = self.try_step() {
+            self.error = Some(err.into());
+            self.finished = true;
+        }
+    }
+
+    fn try_step(&mut self) -> io::Result<()> {
+        if self.unmatchable {
+            self.read_tail()?;
+            self.finish();
+            return Ok(());
+        }
+        let block_size = self.block_size;
+        let buf_len = self.new_len as usize;
+
+        // a normalized window is hashed whole, the rolled checksum is of the raw bytes
+        let sig = self.sig.borrow();
+        let normalized = match sig.is_normalized() {
+            true => Some(sig.normalized(self.window.make_contiguous()).into_owned()),
+            false => None,
+        };
+        let (digest, window) = match &normalized {
+            Some(normalized) => (
+                R::new(&sig.seeded(normalized)).digest(),
+                (&normalized[..], &[][..]),
+            ),
+            None => (self.rs.digest(), self.window.as_slices()),
+        };
+        // A weak hash hit is only a match if the strong hash agrees as well,
+        // otherwise it is treated like any other non-matching window
+        let matched_index = sig.get_chunk_map(digest).and_then(|strong_hashes| {
+            check_strong_hash::<D>(
+                self.consumed_block_index,
+                window,
+                strong_hashes,
+                sig.get_strong_hash_len(),
+                self.reuse_blocks,
+                self.policy,
+                self.start_win / block_size as u64,
+            )
+        });
+        if let Some(new_matched_index) = matched_index {
+            // every byte rolled past was taken, so the new bytes end right before the window
+            debug_assert_eq!(
+                self.new_bytes.byte_index + self.new_bytes.bytes,
+                self.start_win
+            );
+            self.copy_block(new_matched_index, block_size as u64);
+
+            // Since no partial block match, we can move and start fresh with new window 1 block from now
+            // unless less than a whole block is left, those bytes are new
+            if self.end_win as usize + block_size >= buf_len {
+                self.read_tail()?;
+                self.finish();
+            } else {
+                self.start_win += block_size as u64;
+                self.end_win += block_size as u64;
+                // the reader is already right after the matched window
+                let filled = fill_block(&mut self.new_buf, self.window.make_contiguous())?;
+                let window = self.window.make_contiguous();
+                // the reader ended before its length, what it had left is new
+                if filled < block_size {
+                    self.new_bytes.take(&window[..filled], self.keep_content);
+                    self.finish();
+                    return Ok(());
+                }
+                self.rs
+                    .batch_roll(&self.sig.borrow().seeded(window))
+                    .unwrap();
+            }
+        } else {
+            // No match, increment the sliding window if at least 1 byte left
+            // Or add the rest of the file since final window did not match.
+            // end_win is the last byte of the window, the byte after it is read only if it exists
+            if self.end_win as usize + 1 >= buf_len {
+                let (front, back) = self.window.as_slices();
+                self.new_bytes.take(front, self.keep_content);
+                self.new_bytes.take(back, self.keep_content);
+                self.finish();
+            } else {
+                self.new_bytes.take(&[self.window[0]], self.keep_content);
+                self.start_win += 1;
+                self.end_win += 1;
+                if self.new_bytes.bytes >= self.max_literal_run {
+                    let new_bytes =
+                        std::mem::replace(&mut self.new_bytes, Add::new(self.start_win));
+                    self.push(Delta::Add(new_bytes));
+                }
+                let mut new_byte = [0u8];
+                self.new_buf.read_exact(&mut new_byte)?;
+                let old_byte = self.window.pop_front().unwrap();
+                self.window.push_back(new_byte[0]);
+                let sig = self.sig.borrow();
+                self.rs.roll_hash(
+                    Some(sig.seeded_byte(new_byte[0])),
+                    sig.seeded_byte(old_byte),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets blocks already consumed be matched again, see `DiffBuilder::reuse_blocks`
+    fn reusing_blocks(mut self, reuse_blocks: bool) -> Self {
+        self.reuse_blocks = reuse_blocks;
+        self
+    }
+
+    /// Picks among blocks hashing the same by `policy`, see `DiffBuilder::match_policy`
+    fn matching_by(mut self, policy: MatchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Flushes new bytes as an `Add` every `max_literal_run` bytes
+    fn capping_literals(mut self, max_literal_run: usize) -> Self {
+        self.max_literal_run = max_literal_run.max(1) as u64;
+        self
+    }
+
+    /// Leaves the content of `Add`s empty, only their bytes are counted
+    fn without_content(mut self) -> Self {
+        self.keep_content = false;
+        self
+    }
+
+    /// Reads the new file `bytes` at a time, but at least a block, see `DiffBuilder::lookahead`
+    fn reading_ahead(mut self, bytes: usize) -> Self {
+        // nothing is buffered before the first step, the initial window is read past the buffer
+        let new_buf = self.new_buf.into_inner();
+        self.new_buf = BufReader::with_capacity(bytes.max(self.block_size), new_buf);
+        self
+    }
+
+    /// Hands out the new bytes so far and a `Copy` of `bytes` from the block at `block_index`
+    fn copy_block(&mut self, block_index: u64, bytes: u64) {
+        let block_size = self.block_size as u64;
+        // a reused block is only copied, the old file is not consumed any further
+        if block_index as i64 > self.consumed_block_index {
+            // There are blocks in the signature file that are not in new file, needs to be deleted
+            // from the first block after the consumed ones, in the old file like the blocks
+            let advanced_blocks = block_index - (self.consumed_block_index + 1) as u64;
+            if advanced_blocks > 0 {
+                self.push(Delta::Delete(Delete {
+                    byte_index: (self.consumed_block_index + 1) as u64 * block_size,
+                    bytes: advanced_blocks * block_size,
+                }));
+            }
+            // This makes sure that we do not take the same block from the past and use it as a match again
+            self.consumed_block_index = block_index as i64;
+        }
+
+        // Ther are currently new bytes added in the previous loop
+        let new_bytes = std::mem::replace(&mut self.new_bytes, Add::new(self.end_win + 1));
+        self.push(Delta::Add(new_bytes));
+        self.push(Delta::Copy(Copy {
+            src_byte_index: block_index * block_size,
+            bytes,
+            block_index,
+        }));
+    }
+
+    /// Reads the rest of the new file, shorter than a block, as a copy of the short last
+    /// block of the old file if they are the same and as new bytes otherwise
+    fn read_tail(&mut self) -> io::Result<()> {
+        let mut rest = Vec::new();
+        self.new_buf.read_to_end(&mut rest)?;
+        let sig = self.sig.borrow();
+        let last = sig.get_blocks().checked_sub(1);
+        let tail_index = last
+            .filter(|last| {
+                let range = sig.get_block_range(*last);
+                !rest.is_empty() && range.end - range.start == rest.len() as u64
+            })
+            .and_then(|_| sig.get_chunk_map(sig.weak_checksum(&rest)))
+            .and_then(|strong_hashes| {
+                check_strong_hash::<D>(
+                    self.consumed_block_index,
+                    (&sig.normalized(&rest), &[]),
+                    strong_hashes,
+                    sig.get_strong_hash_len(),
+                    self.reuse_blocks,
+                    self.policy,
+                    self.start_win / self.block_size as u64,
+                )
+            })
+            .filter(|block_index| Some(*block_index) == last);
+        match tail_index {
+            Some(block_index) => self.copy_block(block_index, rest.len() as u64),
+            None => self.new_bytes.take(&rest, self.keep_content),
+        }
+        Ok(())
+    }
+
+    /// Queues a delta to be handed out, deltas of no bytes are dropped
+    ///
+    /// `Add`s longer than the maximum literal run are split.
+    fn push(&mut self, delta: Delta) {
+        if let Delta::Add(add) = &delta {
+            debug_assert!(!self.keep_content || add.content.len() as u64 == add.bytes);
+        }
+        match delta {
+            Delta::Add(mut add) if add.bytes > self.max_literal_run => {
+                while add.bytes > 0 {
+                    let bytes = add.bytes.min(self.max_literal_run);
+                    let rest = add.content.split_off(add.content.len().min(bytes as usize));
+                    self.pending.push_back(Delta::Add(Add {
+                        byte_index: add.byte_index,
+                        bytes,
+                        content: std::mem::replace(&mut add.content, rest),
+                    }));
+                    add.byte_index += bytes;
+                    add.bytes -= bytes;
+                }
+            }
+            delta if delta.bytes() > 0 => self.pending.push_back(delta),
+            _ => {}
+        }
+    }
+
+    /// Hands out what is left once the window reached the end of the new file
+    fn finish(&mut self) {
+        // final new bytes
+        let new_bytes = std::mem::replace(&mut self.new_bytes, Add::new(0));
+        self.push(Delta::Add(new_bytes));
+
+        // handlefinal unmatched bytes, they start right after the last consumed block
+        if self.sig.borrow().get_blocks() as i64 - 1 > self.consumed_block_index {
+            let matched_bytes = (self.consumed_block_index + 1) as u64 * self.block_size as u64;
+            self.push(Delta::Delete(Delete {
+                byte_index: matched_bytes,
+                bytes: self.sig.borrow().get_file_size() - matched_bytes,
+            }));
+        }
+        self.finished = true;
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: Digest, R: RollingHash, N: Read + Seek, S: Borrow<Signature<D, R>>> Iterator
+    for DiffIter<D, R, N, S>
+{
+    type Item = Result<Delta, DiffError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_watched(&mut Watch::default())
+    }
+}
+
+/// Progress callback and cancel flag of a diff, see `DiffBuilder::diff_with_progress`
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Watch<'a> {
+    // called with the bytes of the new file passed and its length
+    progress: Option<&'a mut dyn FnMut(u64, u64)>,
+    // bytes of the new file passed when progress was last reported
+    reported: u64,
+    cancel: Option<&'a AtomicBool>,
+}
+
+#[cfg(feature = "std")]
+impl Watch<'_> {
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .is_some_and(|cancel| cancel.load(AtomicOrdering::Relaxed))
+    }
+
+    /// Reports `passed` bytes if the window moved far enough since the last report
+    fn passed(&mut self, passed: u64, new_len: u64) {
+        if let Some(progress) = &mut self.progress {
+            if passed >= self.reported + builder::PROGRESS_INTERVAL {
+                self.reported = passed;
+                progress(passed, new_len);
+            }
+        }
+    }
+
+    fn done(&mut self, new_len: u64) {
+        if let Some(progress) = &mut self.progress {
+            progress(new_len, new_len);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: Digest, R: RollingHash, N: Read + Seek, S: Borrow<Signature<D, R>>> DiffIter<D, R, N, S> {
+    /// Same as `next`, reporting progress to `watch` and stopping once it is cancelled
+    fn next_watched(&mut self, watch: &mut Watch<'_>) -> Option<Result<Delta, DiffError>> {
+        loop {
+            if let Some(delta) = self.pending.pop_front() {
+                return Some(Ok(delta));
+            }
+            if let Some(error) = self.error.take() {
+                return Some(Err(error));
+            }
+            if self.finished {
+                return None;
+            }
+            if watch.cancelled() {
+                self.finished = true;
+                return Some(Err(DiffError::Cancelled));
+            }
+            self.step();
+            watch.passed(self.start_win, self.new_len);
+        }
+    }
+}
+
+/// Rebuilds the new file from the old file and the deltas produced by `check_diffs`
+///
+/// `Add` indexes are positions in the new file and `Delete` and `Copy` indexes are positions
+/// in the old file. `check_diffs` emits a `Copy` for every matched range, but deltas that
+/// leave them out still apply: every byte of the old file not covered by a `Delete` is
+/// copied in order. The deltas hold byte offsets, so `_block_size` is not needed and only
+/// kept for the callers passing it.
+pub fn apply_delta(old: &[u8], _block_size: usize, deltas: &[Delta]) -> Vec<u8> {
+    let mut new = Vec::with_capacity(old.len());
+    // the next byte of the old file that has not been copied or deleted yet
+    let mut old_index = 0usize;
+
+    for delta in deltas {
+        match delta {
+            Delta::Add(add) => {
+                // matched blocks before this insertion
+                let end = old
+                    .len()
+                    .min(old_index + (add.byte_index as usize).saturating_sub(new.len()));
+                new.extend_from_slice(&old[old_index..end]);
+                old_index = end;
+                new.extend_from_slice(&add.content);
+            }
+            Delta::Delete(delete) => {
+                // matched blocks before this deletion
+                let end = old.len().min((delete.byte_index as usize).max(old_index));
+                new.extend_from_slice(&old[old_index..end]);
+                old_index = old.len().min(end + delete.bytes as usize);
+            }
+            Delta::Copy(copy) => {
+                let start = old.len().min(copy.src_byte_index as usize);
+                let end = old.len().min(start + copy.bytes as usize);
+                // matched bytes before this copy that no delta covered
+                if start > old_index {
+                    new.extend_from_slice(&old[old_index..start]);
+                }
+                new.extend_from_slice(&old[start..end]);
+                old_index = old_index.max(end);
+            }
+        }
+    }
+    // the rest of the old file are matched blocks
+    new.extend_from_slice(&old[old_index..]);
+    new
+}
+
+/// Same as `apply_delta` but the rebuilt file has to hash to `new_hash`
+///
+/// `new_hash` is the `strong_hash` of the whole new file as sent along the deltas, e.g. by
+/// `write_verified_delta`.
+pub fn apply_delta_verified(
+    old: &[u8],
+    block_size: usize,
+    deltas: &[Delta],
+    new_hash: &[u8],
+) -> Result<Vec<u8>, VerifyError> {
+    let new = apply_delta(old, block_size, deltas);
+    let got = strong_hash(&new);
+    if got[..] != new_hash[..] {
+        return Err(VerifyError {
+            expected: new_hash.to_vec(),
+            got,
+        });
+    }
+    Ok(new)
+}
+
+/// Same as `apply_delta` but every block a `Copy` takes from `old` is checked first
+///
+/// Each copied block is hashed again and compared with `sig`, the signature the deltas
+/// were diffed against, so a corrupted old file is not patched with. A `Copy` also has to
+/// start at its block and run over whole blocks of the signature, as `apply_delta` copies
+/// by offset. Only `Copy`s are checked, deltas without them leave the matched ranges
+/// unchecked.
+#[cfg(feature = "std")]
+pub fn apply_delta_checked<D: Digest, R: RollingHash>(
+    old: &[u8],
+    sig: &Signature<D, R>,
+    deltas: &[Delta],
+) -> Result<Vec<u8>, BlockMismatch> {
+    for delta in deltas {
+        if let Delta::Copy(copy) = delta {
+            let mut block_index = copy.block_index;
+            let end = copy
+                .src_byte_index
+                .checked_add(copy.bytes)
+                .ok_or(BlockMismatch { block_index })?;
+            let mut start = copy.src_byte_index;
+            // a coalesced copy runs over the blocks following its first one
+            loop {
+                // out of range blocks have no range to look up
+                if block_index >= sig.get_blocks() {
+                    return Err(BlockMismatch { block_index });
+                }
+                let range = sig.get_block_range(block_index);
+                let block = old.get(range.start as usize..range.end as usize);
+                let matches = range.start == start
+                    && block.is_some_and(|block| sig.block_matches(block_index, block));
+                if !matches {
+                    return Err(BlockMismatch { block_index });
+                }
+                if range.end >= end {
+                    break;
+                }
+                start = range.end;
+                block_index += 1;
+            }
+        }
+    }
+    Ok(apply_delta(old, sig.get_block_size(), deltas))
+}
+
+/// Weak checksum of `block` as kept in a `Signature`
+pub fn weak_checksum(block: &[u8]) -> u32 {
+    Rollsum::new(block).digest()
+}
+
+/// Blake2b hash of `block` as kept in a `Signature` before any truncation
+pub fn strong_hash(block: &[u8]) -> Vec<u8> {
+    truncated_hash::<Blake2b>(block, Blake2b::output_size())
+}
+
+/// Blake2b hash of a whole file, e.g. to check it against the one read with a delta
+pub fn whole_file_hash(file: &[u8]) -> Vec<u8> {
+    strong_hash(file)
+}
+
+/// Whether two files are the same, without diffing them
+///
+/// Files of different lengths are told apart before anything is hashed.
+pub fn files_equal_fast(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && whole_file_hash(a) == whole_file_hash(b)
+}
+
+/// The first `len` bytes of the `D` hash of `block`
+pub(crate) fn truncated_hash<D: Digest>(block: &[u8], len: usize) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(block);
+    hasher.finalize()[..len].to_vec()
+}
+
+/// Whether two strong hashes are the same, in time independent of where they differ
+pub(crate) fn hashes_equal(a: &[u8], b: &[u8]) -> bool {
+    use subtle::ConstantTimeEq;
+    bool::from(a.ct_eq(b))
+}
+
+/// Reads until `buf` is full or the reader is exhausted, returning the bytes read
+///
+/// A single `read` may return less than requested before EOF, e.g. for pipes and sockets.
+#[cfg(feature = "std")]
+pub(crate) fn fill_block<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Same as the `std` `fill_block` for the crate's own `Read`
+#[cfg(not(feature = "std"))]
+pub(crate) fn fill_block<R: Read + ?Sized>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<usize, DiffError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Input of `Signature::generate` without the `std` feature, which has no `std::io::Read`
+///
+/// `&[u8]` reads its bytes from the front, other inputs implement it the same way.
+#[cfg(not(feature = "std"))]
+pub trait Read {
+    /// Reads at most `buf.len()` bytes into `buf`, 0 once the input is exhausted
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DiffError>;
+}
+
+#[cfg(not(feature = "std"))]
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DiffError> {
+        let len = buf.len().min(self.len());
+        let (read, rest) = self.split_at(len);
+        buf[..len].copy_from_slice(read);
+        *self = rest;
+        Ok(len)
+    }
+}
+
+/// Index of a block after `consumed_block_index` hashing to `front` then `back`
+///
+/// With `reuse_blocks` an earlier block hashing the same is taken if no later one does.
+/// Among several blocks the `policy` picks one, `near_block` is where the window is.
+/// The window is only hashed if one of the weak hits could be taken.
+#[cfg(feature = "std")]
+pub(crate) fn check_strong_hash<D: Digest>(
+    consumed_block_index: i64,
+    (front, back): (&[u8], &[u8]),
+    blocks: &[BlockHash],
+    strong_hash_len: usize,
+    reuse_blocks: bool,
+    policy: MatchPolicy,
+    near_block: u64,
+) -> Option<u64> {
+    // weak hits on consumed blocks cannot match, no need to hash for them
+    if !reuse_blocks
+        && blocks
+            .iter()
+            .all(|block| block.block_index as i64 <= consumed_block_index)
+    {
+        return None;
+    }
+    // the ring buffer may wrap around, the logical block is the front then the back
+    let mut hasher = D::new();
+    hasher.update(front);
+    hasher.update(back);
+    let hash = hasher.finalize();
+    // only the stored prefix of the strong hash can be compared
+    let matching = blocks
+        .iter()
+        .filter(|block| hashes_equal(&block.hash, &hash[..strong_hash_len]))
+        .map(|block| block.block_index);
+    let next = policy.pick(
+        matching
+            .clone()
+            .filter(|block_index| *block_index as i64 > consumed_block_index),
+        near_block,
+    );
+    if next.is_some() || !reuse_blocks {
+        return next;
+    }
+    policy.pick(matching, near_block)
+}
+/// Checks the parts built without the `std` feature, run with `--no-default-features`
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_tests {
+    use super::*;
+
+    #[test]
+    fn checksums_roll_without_std() {
+        let buf = b"I solemnly swear that I am up to no good";
+        let mut rs = Rollsum::new(&buf[..8]);
+        let mut crc = <Crc32 as RollingHash>::new(&buf[..8]);
+        for start in 1..buf.len() - 8 {
+            rs.roll_hash(Some(buf[start + 7]), buf[start - 1]);
+            crc.roll_hash(Some(buf[start + 7]), buf[start - 1]);
+            assert_eq!(rs.digest(), weak_checksum(&buf[start..start + 8]));
+            assert_eq!(
+                crc.digest(),
+                <Crc32 as RollingHash>::new(&buf[start..start + 8]).digest()
+            );
+        }
+        assert_eq!(strong_hash(buf).len(), Blake2b::output_size());
+    }
+
+    #[test]
+    fn deltas_apply_without_std() {
+        let old = b"Mischief managed";
+        let deltas = coalesce_deltas(vec![
+            Delta::Delete(Delete {
+                byte_index: 0,
+                bytes: 8,
+            }),
+            Delta::Add(Add {
+                byte_index: 0,
+                bytes: 4,
+                content: b"Hogw".to_vec(),
+            }),
+            Delta::Add(Add {
+                byte_index: 4,
+                bytes: 4,
+                content: b"arts".to_vec(),
+            }),
+        ]);
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(apply_delta(old, 8, &deltas), b"Hogwarts managed".to_vec());
+    }
+
+    #[test]
+    fn signatures_generate_without_std() {
+        let old = b"I solemnly swear that I am up to no good";
+        let sig = Signature::from_slice(old, 8).unwrap();
+        assert_eq!(sig.get_blocks(), 5);
+        assert_eq!(sig.get_file_size(), old.len() as u64);
+        assert_eq!(sig.contains_block(b"am up to"), Some(3));
+        assert_eq!(sig.contains_block(b"Mischief"), None);
+
+        let decoded: Signature = Signature::decode(&sig.encode()).unwrap();
+        assert_eq!(decoded.encode(), sig.encode());
+        let mut appended = Signature::new(8);
+        appended.append(&mut &old[..13]).unwrap();
+        appended.append(&mut &old[13..]).unwrap();
+        assert_eq!(appended.encode(), sig.encode());
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// Bytes that practically never repeat a block
+    pub(crate) fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut seed = 42u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Only the changes, the matched ranges in between left out
+    fn without_copies(diffs: Vec<Delta>) -> Vec<Delta> {
+        diffs
+            .into_iter()
+            .filter(|delta| !matches!(delta, Delta::Copy(_)))
+            .collect()
+    }
+
+    /// (block_size, old, new) of every diff scenario covered by the tests below
+    pub(crate) fn corpus() -> Vec<(usize, Vec<u8>, Vec<u8>)> {
+        let large = pseudo_random(100_000);
+        let mut large_new = large.clone();
+        large_new.splice(80_000..80_000, b"not ".iter().cloned());
+
+        let cases: Vec<(usize, &[u8], &[u8])> = vec![
+            (
+                4,
+                b"Anyone can speak Troll. All you have to do is grunt.",
+                b"Anyone can speak Troll. All you have to not do is grunt.",
+            ),
+            (
+                5,
+                b"Make a rolling hash diffing algorithm in Rust",
+                b"a rolling hash diffing algorithm in Rust",
+            ),
+            (
+                7,
+                b"Now repeat after me - repeat after me, Riddikulus",
+                b"Now repeat after me - without wands please - repeat after me, Ridiculous",
+            ),
+            (
+                4,
+                &[1, 2, 3, 4, 10, 20, 30, 40],
+                &[2, 0, 4, 4, 10, 20, 30, 40],
+            ),
+            (
+                5,
+                b"Make a rolling hash diffing algorithm in Rust",
+                &b"Make a rolling hash diffing algorithm in Rust"[..35],
+            ),
+            // the last window would end past the new file
+            (4, b"abcdefgh", b"abcdefg"),
+            // a window equal to the final short block padded with the block before it
+            (4, b"abcdefghab", b"abcdefghabgh"),
+            (8, &large, &large_new),
+        ];
+        cases
+            .into_iter()
+            .map(|(block_size, old, new)| (block_size, old.to_vec(), new.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn it_detects_added_blocks() {
+        let diffs = check_diffs(
+            4,
+            Cursor::new(
+                String::from("Anyone can speak Troll. All you have to do is grunt.").as_bytes(),
+            ),
+            Cursor::new(
+                String::from("Anyone can speak Troll. All you have to not do is grunt.").as_bytes(),
+            ),
+        )
+        .unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 1); // only 1 block change
+        match &diffs[0] {
+            Delta::Add(add) => {
+                assert_eq!(add.byte_index, 40); // index to start inserting
+                assert_eq!(add.bytes, 4); // number of bytes to insert
+                assert_eq!(
+                    // what to insert
+                    String::from_utf8(add.content.clone()).unwrap(),
+                    String::from("not ")
+                );
+            }
+            _ => panic!("Should not be delete"),
+        }
+    }
+
+    #[test]
+    fn it_detects_removed_blocks() {
+        let diffs = check_diffs(
+            5,
+            Cursor::new(String::from("Make a rolling hash diffing algorithm in Rust").as_bytes()),
+            Cursor::new(String::from("a rolling hash diffing algorithm in Rust").as_bytes()),
+        )
+        .unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 1); // only diff
+        match &diffs[0] {
+            Delta::Delete(delete) => {
+                assert_eq!(delete.byte_index, 0); // position to start remove
+                assert_eq!(delete.bytes, 5); // bytes to remove
+            }
+            _ => panic!("Should not be add"),
+        }
+    }
+
+    #[test]
+    fn it_detechs_edits() {
+        let diffs = check_diffs(
+            7,
+            Cursor::new(
+                String::from("Now repeat after me - repeat after me, Riddikulus").as_bytes(),
+            ),
+            Cursor::new(
+                String::from(
+                    "Now repeat after me - without wands please - repeat after me, Ridiculous",
+                )
+                .as_bytes(),
+            ),
+        )
+        .unwrap();
+        let diffs = without_copies(diffs);
+        // 2 additions and 1 deletion
+        assert_eq!(diffs.len(), 3);
+
+        // First insertion content
+        match &diffs[0] {
+            Delta::Add(add) => {
+                assert_eq!(
+                    String::from_utf8(add.content.clone()).unwrap(),
+                    String::from(" without wands please -")
+                );
+            }
+            _ => panic!("Should not be delete"),
+        }
+
+        // Second insertion content
+        match &diffs[1] {
+            Delta::Add(add) => {
+                assert_eq!(
+                    String::from_utf8(add.content.clone()).unwrap(),
+                    String::from("iculous")
+                );
+            }
+            _ => panic!("Should not be delete"),
+        }
+
+        // Deletion bytes
+        match &diffs[2] {
+            Delta::Delete(delete) => {
+                assert_eq!(delete.byte_index, 42);
+                assert_eq!(delete.bytes, String::from("dikulus").len() as u64);
+            }
+            _ => panic!("Should not be add"),
+        }
+    }
+
+    #[test]
+    fn it_keeps_offsets_past_16_bit_boundary() {
+        // ~100 KB of pseudo random bytes so blocks do not repeat
+        let old = pseudo_random(100_000);
+        let mut new = old.clone();
+        // insert on a block boundary well past u16::MAX
+        new.splice(80_000..80_000, b"not ".iter().cloned());
+
+        let diffs = check_diffs(8, Cursor::new(&old[..]), Cursor::new(&new[..])).unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 1);
+        match &diffs[0] {
+            Delta::Add(add) => {
+                assert_eq!(add.byte_index, 80_000);
+                assert_eq!(add.bytes, 4);
+                assert_eq!(add.content, b"not ".to_vec());
+            }
+            _ => panic!("Should not be delete"),
+        }
+    }
+
+    #[test]
+    fn it_rolls_past_weak_hash_collisions() {
+        let old = [1u8, 2, 3, 4, 10, 20, 30, 40];
+        let new = [2u8, 0, 4, 4, 10, 20, 30, 40];
+        // Same weak hash for the first block, different strong hash
+        assert_eq!(
+            Rollsum::new(&old[..4]).digest(),
+            Rollsum::new(&new[..4]).digest()
+        );
+
+        let diffs = check_diffs(4, Cursor::new(&old[..]), Cursor::new(&new[..])).unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 2);
+        match &diffs[0] {
+            Delta::Delete(delete) => assert_eq!(delete.bytes, 4),
+            _ => panic!("Should not be add"),
+        }
+        match &diffs[1] {
+            Delta::Add(add) => {
+                assert_eq!(add.byte_index, 0);
+                assert_eq!(add.content, vec![2, 0, 4, 4]);
+            }
+            _ => panic!("Should not be delete"),
+        }
+    }
+
+    #[test]
+    fn strong_hashes_sharing_a_prefix_do_not_match() {
+        let window = b"Mischief managed";
+        let hash = strong_hash(window);
+        let mut close = hash.clone();
+        *close.last_mut().unwrap() ^= 1;
+        let blocks = [
+            BlockHash {
+                block_index: 0,
+                hash: StrongHash::new(&close),
+            },
+            BlockHash {
+                block_index: 1,
+                hash: StrongHash::new(&hash),
+            },
+        ];
+        let check = |blocks: &[BlockHash]| {
+            check_strong_hash::<Blake2b>(
+                -1,
+                (&window[..], &[]),
+                blocks,
+                hash.len(),
+                false,
+                MatchPolicy::Smallest,
+                0,
+            )
+        };
+        assert_eq!(check(&blocks), Some(1));
+        assert_eq!(check(&blocks[..1]), None);
+        assert!(!hashes_equal(&hash, &hash[..hash.len() - 1]));
+    }
+
+    #[test]
+    fn it_round_trips_through_apply_delta() {
+        for (block_size, old, new) in corpus() {
+            let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            assert_eq!(apply_delta(&old, block_size, &diffs), new);
+
+            // 8 bytes of strong hash are enough for all of them
+            let diffs = DiffBuilder::new()
+                .block_size(block_size)
+                .strong_hash_len(8)
+                .diff(Cursor::new(&old), Cursor::new(&new))
+                .unwrap();
+            assert_eq!(apply_delta(&old, block_size, &diffs), new);
+        }
+    }
+
+    #[test]
+    fn copies_past_uncovered_bytes_copy_them_first() {
+        let old = b"Mischief managed".to_vec();
+        let copy = |src_byte_index, bytes| {
+            Delta::Copy(Copy {
+                src_byte_index,
+                bytes,
+                block_index: src_byte_index / 4,
+            })
+        };
+        assert_eq!(apply_delta(&old, 4, &[copy(8, 4)]), old);
+        let deltas = [
+            copy(4, 4),
+            Delta::Delete(Delete {
+                byte_index: 8,
+                bytes: 4,
+            }),
+            copy(12, 4),
+        ];
+        assert_eq!(apply_delta(&old, 4, &deltas), b"Mischiefaged".to_vec());
+    }
+
+    #[test]
+    fn it_adds_everything_for_empty_old_file() {
+        let new = b"Mischief managed";
+        let diffs = check_diffs(4, Cursor::new(&b""[..]), Cursor::new(&new[..])).unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 1);
+        match &diffs[0] {
+            Delta::Add(add) => {
+                assert_eq!(add.byte_index, 0);
+                assert_eq!(add.bytes, new.len() as u64);
+                assert_eq!(add.content, new.to_vec());
+            }
+            _ => panic!("Should not be delete"),
+        }
+    }
+
+    #[test]
+    fn it_deletes_everything_for_empty_new_file() {
+        let old = b"Mischief managed";
+        let diffs = check_diffs(4, Cursor::new(&old[..]), Cursor::new(&b""[..])).unwrap();
+        assert_eq!(diffs.len(), 1);
+        match &diffs[0] {
+            Delta::Delete(delete) => {
+                assert_eq!(delete.byte_index, 0);
+                assert_eq!(delete.bytes, old.len() as u64);
+            }
+            _ => panic!("Should not be add"),
+        }
+
+        // the window never rolls over an empty new file
+        let lazy: Vec<Delta> = diff_iter(4, Cursor::new(&old[..]), Cursor::new(&b""[..]))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lazy, diffs);
+        assert_eq!(apply_delta(old, 4, &diffs), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn trailing_deletes_cover_the_real_file_size() {
+        // 3 whole blocks and 3 bytes, only the first block is kept
+        let old = b"Mischief managed!";
+        for new in [&b"Misc"[..], b"Mischief"] {
+            let diffs = check_diffs(4, Cursor::new(&old[..15]), Cursor::new(new)).unwrap();
+            let deleted: u64 = diffs
+                .iter()
+                .filter(|delta| matches!(delta, Delta::Delete(_)))
+                .map(Delta::bytes)
+                .sum();
+            assert_eq!(deleted, 15 - new.len() as u64);
+            assert_eq!(apply_delta(&old[..15], 4, &diffs), new);
+        }
+    }
+
+    #[test]
+    fn mismatches_run_up_to_the_end_of_the_new_file() {
+        let old = b"Lumos\r\nNox\r\nAlohomora\r\nExpelliarmus\r\n";
+        for block_size in 1..=8 {
+            for changed in 1..=block_size + 2 {
+                let mut new = old.to_vec();
+                let len = new.len();
+                for byte in &mut new[len - changed..] {
+                    *byte ^= 0x80;
+                }
+                let diffs = check_diffs(block_size, Cursor::new(old), Cursor::new(&new)).unwrap();
+                assert_eq!(apply_delta(old, block_size, &diffs), new);
+                match diffs
+                    .iter()
+                    .rev()
+                    .find(|delta| matches!(delta, Delta::Add(_)))
+                {
+                    Some(Delta::Add(add)) => {
+                        assert_eq!(add.byte_index() + add.bytes(), new.len() as u64)
+                    }
+                    _ => panic!("the changed tail is not added"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normalized_windows_match_normalized_blocks() {
+        let old = b"abcdefghijklmnop";
+        let new = b"ABCDefghIJKLmnopNox";
+        let mut sig = Signature::new(4).with_normalizer(<[u8]>::to_ascii_lowercase);
+        sig.generate(&mut Cursor::new(&old[..])).unwrap();
+        let diffs = diff_against_signature(&sig, Cursor::new(&new)).unwrap();
+        // the added bytes are kept as they are
+        assert_eq!(
+            without_copies(diffs),
+            vec![Delta::Add(Add {
+                byte_index: 16,
+                bytes: 3,
+                content: b"Nox".to_vec(),
+            })]
+        );
+        let unnormalized = check_diffs(4, Cursor::new(&old[..]), Cursor::new(&new)).unwrap();
+        // the upper case blocks are deleted and added again
+        assert_eq!(without_copies(unnormalized).len(), 5);
+    }
+
+    #[test]
+    fn slices_diff_like_readers() {
+        for (block_size, old, new) in corpus() {
+            let diffs = check_diffs_slice(block_size, &old, &new).unwrap();
+            assert_eq!(
+                diffs,
+                check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap()
+            );
+
+            let sig = Signature::from_slice(&old, block_size).unwrap();
+            assert_eq!(
+                sig.encode(),
+                Signature::from_reader(Cursor::new(&old), block_size)
+                    .unwrap()
+                    .encode()
+            );
+            assert_eq!(
+                diff_against_signature(&sig, Cursor::new(&new)).unwrap(),
+                diffs
+            );
+        }
+        let err = Signature::from_slice(b"Nox", 0).unwrap_err();
+        assert_eq!(err, DiffError::InvalidBlockSize);
+    }
+
+    #[test]
+    fn copied_blocks_are_checked_against_the_signature() {
+        for (block_size, old, new) in corpus() {
+            let sig = Signature::from_slice(&old, block_size).unwrap();
+            let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            assert_eq!(apply_delta_checked(&old, &sig, &diffs).unwrap(), new);
+        }
+
+        let old = pseudo_random(10 * 16);
+        let mut new = old.clone();
+        new.splice(40..40, b"Nox".iter().cloned());
+        let sig = Signature::from_slice(&old, 16).unwrap();
+        let diffs = check_diffs(16, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        let mut corrupted = old.clone();
+        corrupted[7 * 16 + 3] ^= 1;
+        assert_eq!(
+            apply_delta_checked(&corrupted, &sig, &diffs).unwrap_err(),
+            BlockMismatch { block_index: 7 }
+        );
+        // a truncated old file misses the copied blocks
+        assert_eq!(
+            apply_delta_checked(&old[..9 * 16], &sig, &diffs).unwrap_err(),
+            BlockMismatch { block_index: 9 }
+        );
+
+        let copy = |src_byte_index, bytes, block_index| {
+            [Delta::Copy(Copy {
+                src_byte_index,
+                bytes,
+                block_index,
+            })]
+        };
+        assert_eq!(
+            apply_delta_checked(&old, &sig, &copy(32, 32, 2)).unwrap(),
+            old
+        );
+        // blocks the signature does not have, also where their offset overflows
+        for block_index in [10, u64::MAX / 4] {
+            assert_eq!(
+                apply_delta_checked(&old, &sig, &copy(0, 16, block_index)).unwrap_err(),
+                BlockMismatch { block_index }
+            );
+        }
+        assert!(apply_delta_checked(&old, &sig, &copy(u64::MAX, 16, 2)).is_err());
+        // a verified block does not cover bytes copied from elsewhere
+        assert_eq!(
+            apply_delta_checked(&old, &sig, &copy(40, 16, 2)).unwrap_err(),
+            BlockMismatch { block_index: 2 }
+        );
+        let mut chunked = Signature::new(0);
+        chunked
+            .generate_chunked(&mut Cursor::new(&old), ContentDefinedChunking::new(64))
+            .unwrap();
+        assert!(apply_delta_checked(&old, &chunked, &copy(0, 16, 1_000)).is_err());
+    }
+
+    #[test]
+    fn deletes_point_into_the_old_file() {
+        // blocks 3 to 5 cut out behind a prefix, so the two files are 3 bytes apart
+        let old = pseudo_random(10 * 16);
+        let mut new = b"Nox".to_vec();
+        new.extend_from_slice(&old[..3 * 16]);
+        new.extend_from_slice(&old[6 * 16..]);
+        let diffs = check_diffs(16, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        let deletes: Vec<&Delete> = diffs
+            .iter()
+            .filter_map(|delta| match delta {
+                Delta::Delete(delete) => Some(delete),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deletes.len(), 1);
+        assert_eq!(deletes[0].byte_index(), 3 * 16);
+        assert_eq!(deletes[0].bytes(), 3 * 16);
+        assert_eq!(apply_delta(&old, 16, &diffs), new);
+    }
+
+    #[test]
+    fn one_signature_is_reused_across_diffs() {
+        let old = pseudo_random(4096);
+        let mut inserted = old.clone();
+        inserted.splice(1000..1000, b"Mischief managed".iter().cloned());
+        let truncated = old[..3000].to_vec();
+
+        let sig = Signature::from_reader(Cursor::new(&old), 16).unwrap();
+        let encoded = sig.encode();
+        for new in [&inserted, &truncated, &inserted] {
+            let diffs = diff_against_signature(&sig, Cursor::new(new)).unwrap();
+            assert_eq!(
+                diffs,
+                check_diffs(16, Cursor::new(&old), Cursor::new(new)).unwrap()
+            );
+            assert_eq!(apply_delta(&old, 16, &diffs), *new);
+        }
+        assert_eq!(sig.encode(), encoded);
+    }
+
+    #[test]
+    fn it_diffs_against_a_decoded_signature() {
+        for (block_size, old, new) in corpus() {
+            let expected = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            let mut sig = Signature::new(block_size);
+            sig.generate(&mut Cursor::new(&old)).unwrap();
+            let encoded = sig.encode();
+            drop(sig);
+            let old_copy = old.clone();
+            drop(old);
+
+            let sig: Signature = Signature::decode(&encoded).unwrap();
+            let diffs = diff_against_signature(&sig, Cursor::new(&new)).unwrap();
+            assert_eq!(diffs, expected);
+            assert_eq!(apply_delta(&old_copy, block_size, &diffs), new);
+        }
+
+        let mut chunked = Signature::new(0);
+        chunked
+            .generate_chunked(
+                &mut Cursor::new(b"Alohomora"),
+                ContentDefinedChunking::new(64),
+            )
+            .unwrap();
+        let diffs = diff_against_signature(&chunked, Cursor::new(b"Alohomora"));
+        assert_eq!(diffs.unwrap_err(), DiffError::InvalidBlockSize);
+    }
+
+    #[test]
+    fn borrowed_deltas_match_owned_ones() {
+        for (block_size, old, new) in corpus() {
+            let owned = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            let borrowed = check_diffs_borrowed(block_size, Cursor::new(&old), &new).unwrap();
+            for delta in &borrowed {
+                if let DeltaRef::Add(add) = delta {
+                    let start = add.byte_index() as usize;
+                    assert_eq!(add.content().as_ptr(), new[start..].as_ptr());
+                }
+            }
+            let borrowed: Vec<Delta> = borrowed.into_iter().map(DeltaRef::into_owned).collect();
+            assert_eq!(borrowed, owned);
+        }
+    }
+
+    #[test]
+    fn it_rejects_signatures_of_other_algorithms() {
+        let (block_size, old, new) = corpus().remove(0);
+        let mut sig = Signature::new(block_size).with_strong_hash_len(16);
+        sig.generate(&mut Cursor::new(&old)).unwrap();
+        let encoded = sig.encode();
+        let sig_id = sig.get_algorithm_id();
+
+        let strong: Signature<Blake2s> = Signature::decode(&encoded).unwrap();
+        let diffs = DiffBuilder::<Blake2s, Rollsum>::with_digest()
+            .diff_against_signature(&strong, Cursor::new(&new));
+        let diff_id = signature::algorithm_id::<Blake2s, Rollsum>();
+        assert_eq!(
+            diffs.unwrap_err(),
+            DiffError::AlgorithmMismatch {
+                expected: diff_id,
+                got: sig_id
+            }
+        );
+
+        let weak: Signature<Blake2b, Crc32> = Signature::decode(&encoded).unwrap();
+        let diffs = DiffBuilder::<Blake2b, Crc32>::with_digest()
+            .diff_against_signature(&weak, Cursor::new(&new));
+        assert!(matches!(
+            diffs.unwrap_err(),
+            DiffError::AlgorithmMismatch { .. }
+        ));
+
+        let same: Signature = Signature::decode(&encoded).unwrap();
+        assert!(diff_against_signature(&same, Cursor::new(&new)).is_ok());
+    }
+
+    #[test]
+    fn stats_add_up_to_the_new_file() {
+        for (block_size, old, new) in corpus() {
+            let (diffs, stats) =
+                diff_with_stats(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            assert_eq!(stats.literal_bytes + stats.matched_bytes, new.len() as u64);
+            assert_eq!(stats.deltas, diffs.len());
+            assert_eq!(apply_delta(&old, block_size, &diffs), new);
+        }
+
+        let (block_size, old, new) = corpus().remove(0);
+        let (_, stats) = diff_with_stats(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        assert_eq!(stats.literal_bytes, 4);
+        assert_eq!(stats.literal_ratio(), 4.0 / new.len() as f64);
+        assert_eq!(DiffStats::new(&[]).literal_ratio(), 0.0);
+    }
+
+    #[test]
+    fn savings_are_the_copied_share_of_the_new_file() {
+        let old = pseudo_random(64 * 1_024);
+        let mut new = old.clone();
+        new.splice(30_000..30_003, b"Nox".iter().cloned());
+        let near = estimate_savings(&old, &new, 64).unwrap();
+        assert!(near > 0.99 && near <= 1.0);
+        assert_eq!(estimate_savings(&old, &old, 64), Ok(1.0));
+
+        let unrelated: Vec<u8> = old.iter().map(|byte| !byte).collect();
+        assert!(estimate_savings(&old, &unrelated, 64).unwrap() < 0.01);
+        assert_eq!(estimate_savings(&old, b"", 64), Ok(0.0));
+    }
+
+    #[test]
+    fn it_rejects_empty_blocks() {
+        for (old, new) in [(&b"Lumos"[..], &b"Nox"[..]), (b"", b""), (b"Lumos", b"")] {
+            let diffs = check_diffs(0, Cursor::new(old), Cursor::new(new));
+            assert_eq!(diffs.unwrap_err(), DiffError::InvalidBlockSize);
+            let mut errors = diff_iter(0, Cursor::new(old), Cursor::new(new));
+            assert_eq!(
+                errors.next().unwrap().unwrap_err(),
+                DiffError::InvalidBlockSize
+            );
+            assert!(errors.next().is_none());
+        }
+        let diffs = DiffBuilder::new()
+            .block_size(0)
+            .diff(Cursor::new(&b"Lumos"[..]), Cursor::new(&b"Nox"[..]));
+        assert_eq!(diffs.unwrap_err(), DiffError::InvalidBlockSize);
+    }
+
+    #[test]
+    fn it_rejects_inputs_shorter_than_a_block() {
+        let diffs = check_diffs(
+            8,
+            Cursor::new(&b"Nox"[..]),
+            Cursor::new(&b"Lumos Maxima"[..]),
+        );
+        assert_eq!(
+            diffs.unwrap_err(),
+            DiffError::InputTooSmall { needed: 8, got: 3 }
+        );
+    }
+
+    #[test]
+    fn it_replaces_everything_for_new_file_shorter_than_a_block() {
+        let old = b"Lumos Maxima";
+        let diffs = check_diffs(8, Cursor::new(&old[..]), Cursor::new(&b"Nox"[..])).unwrap();
+        assert_eq!(diffs.len(), 2);
+        match &diffs[0] {
+            Delta::Add(add) => {
+                assert_eq!(add.byte_index, 0);
+                assert_eq!(add.bytes, 3);
+                assert_eq!(add.content, b"Nox".to_vec());
+            }
+            _ => panic!("Should be add"),
+        }
+        match &diffs[1] {
+            Delta::Delete(delete) => {
+                assert_eq!(delete.byte_index, 0);
+                assert_eq!(delete.bytes, old.len() as u64);
+            }
+            _ => panic!("Should be delete"),
+        }
+        assert_eq!(apply_delta(old, 8, &diffs), b"Nox".to_vec());
+    }
+
+    #[test]
+    fn it_round_trips_with_other_digests() {
+        let old = b"Now repeat after me - repeat after me, Riddikulus";
+        let new = b"Now repeat after me - without wands please - repeat after me, Ridiculous";
+
+        let blake2b =
+            check_diffs_with_digest::<Blake2b>(7, Cursor::new(old), Cursor::new(new)).unwrap();
+        assert_eq!(apply_delta(old, 7, &blake2b), new.to_vec());
+
+        let blake2s =
+            check_diffs_with_digest::<Blake2s>(7, Cursor::new(old), Cursor::new(new)).unwrap();
+        assert_eq!(apply_delta(old, 7, &blake2s), new.to_vec());
+    }
+
+    /// Hands out at most 3 bytes per read
+    struct ChunkedReader<'a>(&'a [u8]);
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn one_block_new_files_are_diffed_whole() {
+        let old = pseudo_random(64 * 4);
+        for new in [
+            old[64..128].to_vec(),
+            old[..64].iter().map(|b| !b).collect(),
+        ] {
+            let deltas = check_diffs(64, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            assert_eq!(apply_delta(&old, 64, &deltas), new);
+            let stats = DiffStats::new(&deltas);
+            assert_eq!(stats.literal_bytes + stats.matched_bytes, new.len() as u64);
+        }
+        let matched = check_diffs(64, Cursor::new(&old), Cursor::new(&old[64..128])).unwrap();
+        assert!(matches!(
+            matched[..],
+            [Delta::Delete(_), Delta::Copy(_), Delta::Delete(_)]
+        ));
+    }
 
-use rollsum::Rollsum;
-use signature::{BlockHash, Signature};
+    /// Claims `len` bytes when sought to its end, but holds only `bytes`
+    struct Shrunk {
+        bytes: Cursor<Vec<u8>>,
+        len: u64,
+    }
 
-#[derive(Debug)]
-pub enum Delta {
-    Add(Add),
-    Delete(Delete),
-}
+    impl Read for Shrunk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.bytes.read(buf)
+        }
+    }
 
-#[derive(Debug, PartialEq)]
-pub struct Add {
-    /// The first byte index to insert / delete the content
-    byte_index: u16,
-    /// Total bytes to be inserted / deleted
-    bytes: u16,
-    /// Content to be inserted
-    content: Vec<u8>,
-}
+    impl Seek for Shrunk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::End(0) => Ok(self.len),
+                pos => self.bytes.seek(pos),
+            }
+        }
+    }
 
-#[derive(Debug, PartialEq)]
-pub struct Delete {
-    /// The first byte index to insert / delete the content
-    byte_index: u16,
-    /// Total bytes to be inserted / deleted
-    bytes: u16,
-}
+    #[test]
+    fn new_files_ending_early_end_the_diff() {
+        let old = pseudo_random(64 * 4);
+        let new = Shrunk {
+            bytes: Cursor::new(old[..64 + 10].to_vec()),
+            len: 64 * 4,
+        };
+        let deltas: Vec<Delta> = diff_iter(64, Cursor::new(&old), new)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(apply_delta(&old, 64, &deltas), old[..64 + 10].to_vec());
+    }
 
-impl Add {
-    pub fn new(byte_index: u16) -> Self {
-        Self {
-            byte_index,
-            bytes: 0,
-            content: Vec::new(),
+    #[test]
+    fn new_files_ending_in_their_first_block_are_new() {
+        let old = pseudo_random(64 * 4);
+        let new = Shrunk {
+            bytes: Cursor::new(old[..10].to_vec()),
+            len: 64 * 4,
+        };
+        let deltas: Vec<Delta> = diff_iter(64, Cursor::new(&old), new)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(apply_delta(&old, 64, &deltas), old[..10].to_vec());
+        assert!(deltas.iter().all(|delta| !matches!(delta, Delta::Copy(_))));
+    }
+
+    /// Fails every read past its first `good` bytes
+    struct Failing {
+        bytes: Cursor<Vec<u8>>,
+        good: u64,
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let left = self.good.saturating_sub(self.bytes.position()) as usize;
+            if left == 0 {
+                return Err(io::Error::new(ErrorKind::BrokenPipe, "Nox"));
+            }
+            let len = buf.len().min(left);
+            self.bytes.read(&mut buf[..len])
         }
     }
-}
 
-pub fn check_diffs(
-    block_size: usize,
-    mut old_buf: Cursor<&[u8]>,
-    mut new_buf: Cursor<&[u8]>,
-) -> Vec<Delta> {
-    // TODO check if old_buf and new_buf is large enough for one block
+    impl Seek for Failing {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.bytes.seek(pos)
+        }
+    }
 
-    // slideing window through new file
-    let mut window = vec![0u8; block_size];
-    let mut start_win = 0u16;
-    let mut end_win = (block_size - 1) as u16;
+    #[test]
+    fn failed_reads_end_the_diff_with_an_error() {
+        let old = pseudo_random(64 * 8);
+        for &good in &[10, 64 * 3, 64 * 3 + 10] {
+            let new = Failing {
+                bytes: Cursor::new(old.clone()),
+                good,
+            };
+            let mut diffs = diff_iter(64, Cursor::new(&old), new);
+            let err = diffs.by_ref().find_map(Result::err);
+            assert_eq!(err, Some(DiffError::Io(ErrorKind::BrokenPipe)));
+            assert!(diffs.next().is_none());
+            let new = Failing {
+                bytes: Cursor::new(old.clone()),
+                good,
+            };
+            assert_eq!(
+                DiffBuilder::new()
+                    .block_size(64)
+                    .diff(Cursor::new(&old), new)
+                    .unwrap_err(),
+                DiffError::Io(ErrorKind::BrokenPipe)
+            );
+        }
+    }
 
-    // returned delta data
-    let mut deltas = Vec::new();
-    let mut new_bytes = Add::new(start_win);
+    #[test]
+    fn fill_block_fills_across_short_reads() {
+        let mut reader = ChunkedReader(b"Expecto Patronum");
+        let mut block = vec![0u8; 7];
+        assert_eq!(fill_block(&mut reader, &mut block).unwrap(), 7);
+        assert_eq!(block, b"Expecto".to_vec());
+        assert_eq!(fill_block(&mut reader, &mut block).unwrap(), 7);
+        assert_eq!(block, b" Patron".to_vec());
+        // final short block
+        assert_eq!(fill_block(&mut reader, &mut block).unwrap(), 2);
+        assert_eq!(&block[..2], b"um");
+        assert_eq!(fill_block(&mut reader, &mut block).unwrap(), 0);
+    }
 
-    // the last block consumed of the Signature file, start before block zero
-    let mut consumed_block_index = -1i32;
+    #[test]
+    fn it_rolls_through_large_mismatching_buffers() {
+        // the sliding window has to roll byte by byte through ~50 KB of new content
+        let block_size = 4096;
+        let old = pseudo_random(50_000);
+        let new: Vec<u8> = old[..block_size]
+            .iter()
+            .cloned()
+            .chain(old[block_size..].iter().map(|b| !b))
+            .collect();
 
-    let mut sig = Signature::new(block_size);
-    sig.generate(&mut old_buf);
+        let diffs = check_diffs(block_size, Cursor::new(&old[..]), Cursor::new(&new[..])).unwrap();
+        let diffs = without_copies(diffs);
+        match &diffs[0] {
+            Delta::Add(add) => {
+                assert_eq!(add.byte_index, block_size as u64);
+                assert_eq!(add.content, new[block_size..].to_vec());
+            }
+            _ => panic!("Should not be delete"),
+        }
+        assert_eq!(apply_delta(&old, block_size, &diffs), new);
+    }
 
-    // initial window and its weak hash
-    new_buf.read(&mut window).unwrap();
-    let mut rs = Rollsum::new(&window);
-    let buf_len = new_buf.get_ref().len();
+    #[test]
+    fn recommended_block_size_grows_within_bounds() {
+        assert_eq!(recommended_block_size(0), 16);
+        assert_eq!(recommended_block_size(100), 16);
+        assert_eq!(recommended_block_size(1 << 20), 1024);
+        assert_eq!(recommended_block_size(1 << 40), 4096);
 
-    // Start to loop through the file
-    loop {
-        if let Some(strong_hashes) = sig.get_chunk_map(rs.digest()) {
-            if let Some(new_matched_index) =
-                check_strong_hash(consumed_block_index, &window, &strong_hashes)
-            {
-                // There are blocks in the signature file that are not in new file, needs to be deleted
-                let advanced_blocks = new_matched_index - (consumed_block_index + 1) as u16;
-                if advanced_blocks > 0 {
-                    deltas.push(Delta::Delete(Delete {
-                        byte_index: start_win,
-                        bytes: (advanced_blocks) * block_size as u16,
-                    }));
-                }
-                // This makes sure that we do not take the same block from the past and use it as a match again
-                consumed_block_index = new_matched_index as i32;
+        let mut previous = 0;
+        for file_len in (0..(1 << 26)).step_by(9973) {
+            let block_size = recommended_block_size(file_len);
+            assert!(block_size >= previous);
+            assert!(block_size.is_power_of_two());
+            previous = block_size;
+        }
+    }
 
-                // Ther are currently new bytes added in the previous loop
-                if new_bytes.bytes > 0 {
-                    deltas.push(Delta::Add(new_bytes));
-                }
-                new_bytes = Add::new(end_win + 1);
+    #[test]
+    fn it_picks_block_size_from_old_file() {
+        let old = pseudo_random(16_384);
+        let mut new = old.clone();
+        new.splice(4096..4096, b"not ".iter().cloned());
+        let diffs = check_diffs_auto(Cursor::new(&old[..]), Cursor::new(&new[..])).unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            apply_delta(&old, recommended_block_size(old.len()), &diffs),
+            new
+        );
+    }
 
-                // Since no partial block match, we can move and start fresh with new window 1 block from now
-                if end_win as usize + block_size > buf_len {
-                    new_bytes.bytes = buf_len as u16 - end_win - 1;
-                    new_bytes.byte_index = end_win + 1;
-                    new_bytes.content = new_buf.get_ref()[end_win as usize..].to_owned();
-                    break;
-                } else {
-                    start_win += block_size as u16;
-                    end_win += block_size as u16;
-                    new_buf.set_position(start_win as u64);
-                    new_buf.read(&mut window).unwrap();
-                    rs.batch_roll(&window).unwrap();
+    #[test]
+    fn it_deletes_trailing_blocks_from_their_first_byte() {
+        let diffs = check_diffs(
+            5,
+            Cursor::new(&b"Make a rolling hash diffing algorithm in Rust"[..]),
+            Cursor::new(&b"Make a rolling hash diffing algorithm in Rust"[..35]),
+        )
+        .unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 1);
+        match &diffs[0] {
+            Delta::Delete(delete) => {
+                assert_eq!(delete.byte_index, 35); // first byte of " in Rust" block
+                assert_eq!(delete.bytes, 10);
+            }
+            _ => panic!("Should not be add"),
+        }
+    }
+
+    #[test]
+    fn diff_iter_yields_same_deltas_as_check_diffs() {
+        for (block_size, old, new) in corpus() {
+            let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            let lazy: Vec<Delta> = diff_iter(block_size, Cursor::new(&old), Cursor::new(&new))
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(coalesce_deltas(lazy), diffs);
+        }
+
+        let mut errors = diff_iter(
+            8,
+            Cursor::new(&b"Nox"[..]),
+            Cursor::new(&b"Lumos Maxima"[..]),
+        );
+        assert_eq!(
+            errors.next().unwrap().unwrap_err(),
+            DiffError::InputTooSmall { needed: 8, got: 3 }
+        );
+        assert!(errors.next().is_none());
+    }
+
+    #[test]
+    fn it_never_emits_empty_deltas() {
+        for (block_size, old, new) in corpus() {
+            let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            assert!(diffs.iter().all(|delta| delta.bytes() > 0));
+            let lazy = diff_iter(block_size, Cursor::new(&old), Cursor::new(&new));
+            assert!(lazy.map(Result::unwrap).all(|delta| delta.bytes() > 0));
+        }
+        // the last window matches with nothing after it
+        let old = pseudo_random(64);
+        let diffs = check_diffs(16, Cursor::new(&old), Cursor::new(&old[16..])).unwrap();
+        assert!(diffs.iter().all(|delta| delta.bytes() > 0));
+    }
+
+    #[test]
+    fn it_diffs_byte_by_byte_at_block_size_one() {
+        let cases: [(&[u8], &[u8]); 6] = [
+            (b"Expelliarmus", b"Expelliarmus!"),
+            (b"Wingardium", b"Leviosa"),
+            (b"abc", b"cba"),
+            (b"a", b"b"),
+            (b"a", b"a"),
+            (b"aab", b"ab"),
+        ];
+        for (old, new) in cases.iter() {
+            let diffs = check_diffs(1, Cursor::new(old), Cursor::new(new)).unwrap();
+            assert_eq!(apply_delta(old, 1, &diffs), new.to_vec());
+            assert!(diffs.iter().all(|delta| delta.bytes() > 0));
+            let lazy = diff_iter(1, Cursor::new(old), Cursor::new(new));
+            let lazy: Vec<Delta> = lazy.map(Result::unwrap).collect();
+            assert_eq!(apply_delta(old, 1, &lazy), new.to_vec());
+        }
+        let diffs = check_diffs(1, Cursor::new(&b"aab"[..]), Cursor::new(&b"ab"[..])).unwrap();
+        let diffs: Vec<String> = diffs.iter().map(Delta::to_string).collect();
+        assert_eq!(
+            diffs,
+            [
+                "=@0 1 bytes from block 0",
+                "-@1 1 bytes",
+                "=@2 1 bytes from block 2"
+            ]
+        );
+
+        let old = pseudo_random(300);
+        let mut new = old.clone();
+        new.retain(|byte| byte % 7 != 0);
+        new.insert(100, 0);
+        let diffs = check_diffs(1, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        assert_eq!(apply_delta(&old, 1, &diffs), new);
+    }
+
+    #[test]
+    fn it_emits_nothing_for_identical_files() {
+        let old = pseudo_random(64 * 100);
+        let diffs = check_diffs(64, Cursor::new(&old[..]), Cursor::new(&old[..])).unwrap();
+        assert!(diffs.is_empty());
+        assert_eq!(apply_delta(&old, 64, &diffs), old);
+
+        let diffs =
+            check_diffs(4, Cursor::new(&b"Lumos"[..4]), Cursor::new(&b"Lumos"[..4])).unwrap();
+        assert!(diffs.is_empty());
+    }
+
+    #[test]
+    fn block_hashes_match_hand_computed_values() {
+        // s = 97 + 98 + 99, ss = 97 + 195 + 294
+        assert_eq!(weak_checksum(b"abc"), 586 << 16 | 294);
+        assert_eq!(weak_checksum(b""), 0);
+        // BLAKE2b-512("abc") from RFC 7693
+        let expected = [
+            0xba, 0x80, 0xa5, 0x3f, 0x98, 0x1c, 0x4d, 0x0d, 0x6a, 0x27, 0x97, 0xb6, 0x9f, 0x12,
+            0xf6, 0xe9, 0x4c, 0x21, 0x2f, 0x14, 0x68, 0x5a, 0xc4, 0xb7, 0x4b, 0x12, 0xbb, 0x6f,
+            0xdb, 0xff, 0xa2, 0xd1, 0x7d, 0x87, 0xc5, 0x39, 0x2a, 0xab, 0x79, 0x2d, 0xc2, 0x52,
+            0xd5, 0xde, 0x45, 0x33, 0xcc, 0x95, 0x18, 0xd3, 0x8a, 0xa8, 0xdb, 0xf1, 0x92, 0x5a,
+            0xb9, 0x23, 0x86, 0xed, 0xd4, 0x00, 0x99, 0x23,
+        ];
+        assert_eq!(strong_hash(b"abc"), expected.to_vec());
+
+        let mut sig = Signature::new(3);
+        sig.generate(&mut Cursor::new(&b"abc"[..])).unwrap();
+        let blocks = sig.get_chunk_map(weak_checksum(b"abc")).unwrap();
+        assert_eq!(blocks[0].hash[..], strong_hash(b"abc")[..]);
+    }
+
+    #[test]
+    fn adds_start_at_their_first_new_byte() {
+        let old = pseudo_random(256);
+        let mut new = old.clone();
+        // rolled over byte by byte right before a match, with a run cap flushing in between
+        new.splice(160..160, b"Mischief managed".iter().cloned());
+        new.splice(37..37, b"Lumos".iter().cloned());
+        for max_literal_run in [3, usize::MAX] {
+            let deltas = DiffBuilder::new()
+                .block_size(16)
+                .coalesce(false)
+                .max_literal_run(max_literal_run)
+                .diff(Cursor::new(&old), Cursor::new(&new))
+                .unwrap();
+            let mut new_len = 0;
+            for delta in &deltas {
+                match delta {
+                    Delta::Add(add) => {
+                        assert_eq!(add.byte_index(), new_len);
+                        let start = add.byte_index() as usize;
+                        assert_eq!(&new[start..start + add.content().len()], add.content());
+                        new_len += add.bytes();
+                    }
+                    Delta::Copy(copy) => new_len += copy.bytes(),
+                    Delta::Delete(_) => {}
                 }
             }
-        // TODO HANDLE IF NO STRONG MATCH
-        } else {
-            // No match, increment the sliding window if at least 1 byte left
-            // Or add the rest of the file since final window did not match
-            new_bytes.content.push(window[0]);
-            new_bytes.bytes += 1;
-            if end_win as usize >= buf_len - 1 {
-                new_bytes.bytes += buf_len as u16 - start_win;
-                new_bytes.content = new_buf.get_ref()[start_win as usize..].to_owned();
-                break;
-            } else {
-                start_win += 1;
-                end_win += 1;
-                window.push(new_buf.get_ref()[(end_win) as usize]);
-                window.remove(0);
-                rs.roll_hash(
-                    Some(new_buf.get_ref()[(end_win) as usize]),
-                    new_buf.get_ref()[start_win as usize - 1],
-                );
+            assert_eq!(new_len, new.len() as u64);
+        }
+    }
+
+    #[test]
+    fn added_content_matches_the_added_bytes() {
+        for (block_size, old, new) in corpus() {
+            let deltas = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            let uncoalesced = diff_iter(block_size, Cursor::new(&old), Cursor::new(&new));
+            let chunked = check_diffs_chunked(
+                ContentDefinedChunking::new(64),
+                Cursor::new(&old),
+                Cursor::new(&new),
+            );
+            let lines = diff_lines(&old, &new);
+            let capped = DiffBuilder::new()
+                .block_size(block_size)
+                .max_literal_run(3)
+                .diff(Cursor::new(&old), Cursor::new(&new))
+                .unwrap();
+            let emitted = deltas
+                .into_iter()
+                .chain(uncoalesced.map(Result::unwrap))
+                .chain(chunked)
+                .chain(lines)
+                .chain(capped);
+            for delta in emitted {
+                if let Delta::Add(add) = delta {
+                    assert_eq!(add.content().len(), add.bytes() as usize);
+                }
             }
         }
     }
-    // final new bytes
-    if new_bytes.bytes > 0 {
-        deltas.push(Delta::Add(new_bytes));
+
+    thread_local! {
+        static STRONG_HASHES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
     }
 
-    // handlefinal unmatched bytes
-    if sig.get_blocks() - 1 > consumed_block_index as u16 {
-        deltas.push(Delta::Delete(Delete {
-            byte_index: ((consumed_block_index + 1) as usize * block_size - 1) as u16,
-            bytes: sig.get_file_size() - (consumed_block_index + 1) as u16 * block_size as u16,
-        }));
+    /// Blake2b counting the hashes finalized on this thread
+    #[derive(Clone, Default)]
+    struct CountingBlake2b(Blake2b);
+
+    impl blake2::digest::Update for CountingBlake2b {
+        fn update(&mut self, data: impl AsRef<[u8]>) {
+            blake2::digest::Update::update(&mut self.0, data);
+        }
     }
-    deltas
-}
 
-fn check_strong_hash(
-    consumed_block_index: i32,
-    window: &[u8],
-    blocks: &Vec<BlockHash>,
-) -> Option<u16> {
-    let mut blake_hasher = Blake2b::new();
-    blake_hasher.update(window);
-    let hash = blake_hasher.finalize();
-    for block in blocks {
-        if block.hash.eq(&hash.as_slice()) {
-            if block.block_index as i32 > consumed_block_index {
-                return Some(block.block_index);
-            }
+    impl blake2::digest::FixedOutput for CountingBlake2b {
+        type OutputSize = <Blake2b as blake2::digest::FixedOutput>::OutputSize;
+
+        fn finalize_into(self, out: &mut blake2::digest::Output<Self>) {
+            STRONG_HASHES.with(|count| count.set(count.get() + 1));
+            self.0.finalize_into(out);
+        }
+
+        fn finalize_into_reset(&mut self, out: &mut blake2::digest::Output<Self>) {
+            STRONG_HASHES.with(|count| count.set(count.get() + 1));
+            self.0.finalize_into_reset(out);
         }
     }
-    None
-}
-#[cfg(test)]
-mod tests {
-    use super::*;
+
+    impl blake2::digest::Reset for CountingBlake2b {
+        fn reset(&mut self) {
+            blake2::digest::Reset::reset(&mut self.0);
+        }
+    }
+
     #[test]
-    fn it_detects_added_blocks() {
-        let diffs = check_diffs(
-            4,
-            Cursor::new(
-                String::from("Anyone can speak Troll. All you have to do is grunt.").as_bytes(),
-            ),
-            Cursor::new(
-                String::from("Anyone can speak Troll. All you have to not do is grunt.").as_bytes(),
-            ),
+    fn weak_hits_on_consumed_blocks_are_not_hashed() {
+        // the last block first, then every block again as weak hits that cannot match
+        let old = pseudo_random(4096);
+        let mut new = old[4080..].to_vec();
+        for _ in 0..10 {
+            new.extend_from_slice(&old);
+        }
+        let hashes = |new: &[u8]| {
+            STRONG_HASHES.with(|count| count.set(0));
+            check_diffs_with_digest::<CountingBlake2b>(16, Cursor::new(&old), Cursor::new(new))
+                .unwrap();
+            STRONG_HASHES.with(|count| count.get())
+        };
+        // each of the 256 blocks hashed for the signature and once more to match
+        let unchanged = hashes(&old);
+        // the 2560 weak hits after matching the last block are skipped, only that one
+        // match is hashed
+        assert_eq!(hashes(&new), hashes(&old[4080..]));
+        assert_eq!(hashes(&new), unchanged - 255);
+        assert_eq!(
+            check_diffs_with_digest::<CountingBlake2b>(16, Cursor::new(&old), Cursor::new(&new)),
+            check_diffs(16, Cursor::new(&old), Cursor::new(&new))
         );
-        assert_eq!(diffs.len(), 1); // only 1 block change
-        match &diffs[0] {
-            Delta::Add(add) => {
-                assert_eq!(add.byte_index, 40); // index to start inserting
-                assert_eq!(add.bytes, 4); // number of bytes to insert
-                assert_eq!(
-                    // what to insert
-                    String::from_utf8(add.content.clone()).unwrap(),
-                    String::from("not ")
-                );
+    }
+
+    #[test]
+    fn files_are_compared_by_their_whole_hash() {
+        let old = pseudo_random(10_000);
+        let mut new = old.clone();
+        assert!(files_equal_fast(&old, &new));
+        assert_eq!(whole_file_hash(&old), whole_file_hash(&new));
+
+        new[5_000] ^= 1;
+        assert!(!files_equal_fast(&old, &new));
+        assert_ne!(whole_file_hash(&old), whole_file_hash(&new));
+        assert!(!files_equal_fast(&old, &old[1..]));
+        assert!(files_equal_fast(b"", b""));
+    }
+
+    #[test]
+    fn copies_point_at_their_signature_blocks() {
+        let (block_size, old, new) = corpus().pop().unwrap();
+        let mut sig = Signature::new(block_size);
+        sig.generate(&mut Cursor::new(&old)).unwrap();
+
+        let diffs = diff_iter(block_size, Cursor::new(&old), Cursor::new(&new));
+        for delta in diffs.map(Result::unwrap) {
+            if let Delta::Copy(copy) = delta {
+                let start = copy.src_byte_index() as usize;
+                assert_eq!(start, copy.block_index() as usize * block_size);
+                let block = &old[start..start + copy.bytes() as usize];
+                let stored = sig
+                    .get_chunk_map(weak_checksum(block))
+                    .unwrap()
+                    .iter()
+                    .find(|stored| stored.block_index == copy.block_index())
+                    .unwrap();
+                assert_eq!(stored.hash[..], strong_hash(block)[..]);
             }
-            _ => panic!("Should not be delete"),
         }
     }
 
     #[test]
-    fn it_detects_removed_blocks() {
+    fn it_adds_the_bytes_after_the_last_whole_window() {
         let diffs = check_diffs(
-            5,
-            Cursor::new(String::from("Make a rolling hash diffing algorithm in Rust").as_bytes()),
-            Cursor::new(String::from("a rolling hash diffing algorithm in Rust").as_bytes()),
+            4,
+            Cursor::new(&b"abcdefgh"[..]),
+            Cursor::new(&b"abcdefg"[..]),
         );
-        assert_eq!(diffs.len(), 1); // only diff
+        let diffs = without_copies(diffs.unwrap());
+        assert_eq!(diffs.len(), 2);
         match &diffs[0] {
+            Delta::Add(add) => {
+                assert_eq!(add.byte_index, 4);
+                assert_eq!(add.content, b"efg".to_vec());
+            }
+            _ => panic!("Should not be delete"),
+        }
+        match &diffs[1] {
             Delta::Delete(delete) => {
-                assert_eq!(delete.byte_index, 0); // position to start remove
-                assert_eq!(delete.bytes, 5); // bytes to remove
+                assert_eq!(delete.byte_index, 4);
+                assert_eq!(delete.bytes, 4);
             }
             _ => panic!("Should not be add"),
         }
     }
 
     #[test]
-    fn it_detechs_edits() {
-        let diffs = check_diffs(
-            7,
-            Cursor::new(
-                String::from("Now repeat after me - repeat after me, Riddikulus").as_bytes(),
-            ),
-            Cursor::new(
-                String::from(
-                    "Now repeat after me - without wands please - repeat after me, Ridiculous",
-                )
-                .as_bytes(),
+    fn it_does_not_match_the_short_last_block_by_its_padding() {
+        let old = b"abcdefghab";
+        let new = b"abcdefghabgh";
+        let diffs = check_diffs(4, Cursor::new(&old[..]), Cursor::new(&new[..])).unwrap();
+        assert_eq!(apply_delta(old, 4, &diffs), new.to_vec());
+    }
+
+    #[test]
+    fn it_matches_the_unchanged_short_last_block() {
+        // 2 whole blocks and 2 bytes
+        let old = b"abcdefghij";
+        let diffs = check_diffs(4, Cursor::new(&old[..]), Cursor::new(&old[..])).unwrap();
+        assert!(diffs.is_empty());
+
+        let cases: [(&[u8], &[&str]); 3] = [
+            (
+                b"abcdXefghij",
+                &[
+                    "=@0 4 bytes from block 0",
+                    "+@4 1 bytes: \"X\"",
+                    "=@4 6 bytes from block 1",
+                ],
             ),
+            (b"efghij", &["-@0 4 bytes", "=@4 6 bytes from block 1"]),
+            (b"ij", &["-@0 8 bytes", "=@8 2 bytes from block 2"]),
+        ];
+        for (new, expected) in cases.iter() {
+            let diffs = check_diffs(4, Cursor::new(&old[..]), Cursor::new(new)).unwrap();
+            let lines: Vec<String> = diffs.iter().map(Delta::to_string).collect();
+            assert_eq!(lines, *expected);
+            assert_eq!(apply_delta(old, 4, &diffs), new.to_vec());
+        }
+    }
+
+    #[test]
+    fn deltas_compare_by_byte_index() {
+        let add = |byte_index, content: &[u8]| {
+            Delta::Add(Add {
+                byte_index,
+                bytes: content.len() as u64,
+                content: content.to_vec(),
+            })
+        };
+        let delete = |byte_index, bytes| Delta::Delete(Delete { byte_index, bytes });
+        let mut deltas = vec![
+            delete(4, 2),
+            add(4, b"Nox"),
+            add(0, b"Lumos"),
+            delete(4, 2),
+            add(4, b"Lumos"),
+        ];
+        deltas.sort();
+        deltas.dedup();
+        assert_eq!(
+            deltas,
+            [
+                add(0, b"Lumos"),
+                add(4, b"Nox"),
+                add(4, b"Lumos"),
+                delete(4, 2)
+            ]
         );
-        // 2 additions and 1 deletion
-        assert_eq!(diffs.len(), 3);
+        assert!(add(4, b"Lumos") > add(4, b"Nox"));
+        assert_ne!(add(4, b"Nox"), add(4, b"Lox"));
+    }
 
-        // First insertion content
-        match &diffs[0] {
+    #[test]
+    fn deltas_display_their_ranges() {
+        let (block_size, old, new) = corpus().remove(0);
+        let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        let lines: Vec<String> = diffs.iter().map(|delta| delta.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "=@0 40 bytes from block 0",
+                "+@40 4 bytes: \"not \"",
+                "=@40 12 bytes from block 10"
+            ]
+        );
+
+        let (block_size, old, new) = corpus().remove(1);
+        let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        assert_eq!(diffs[0].to_string(), "-@0 5 bytes");
+
+        let long = Delta::Add(Add {
+            byte_index: 0,
+            bytes: 40,
+            content: vec![b'a'; 40],
+        });
+        assert_eq!(
+            long.to_string(),
+            format!("+@0 40 bytes: {:?}...", "a".repeat(32))
+        );
+    }
+
+    #[test]
+    fn added_content_debugs_as_text_or_hex() {
+        let (block_size, old, new) = corpus().remove(0);
+        let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        assert!(format!("{:?}", diffs[1]).contains("content: \"not \""));
+        assert_eq!(
+            format!("{:?}", diffs[1]),
+            "Add(Add { byte_index: 40, bytes: 4, content: \"not \" })"
+        );
+
+        let mut binary = Add::new(0);
+        binary.extend(&[0, 0xff, 0x10]);
+        assert_eq!(
+            format!("{:?}", binary),
+            "Add { byte_index: 0, bytes: 3, content: 00ff10 }"
+        );
+        let mut long = Add::new(0);
+        long.extend("é".repeat(20).as_bytes());
+        // the 32 bytes shown end on a whole character
+        assert_eq!(
+            format!("{:?}", long),
+            format!(
+                "Add {{ byte_index: 0, bytes: 40, content: {:?}... (40 bytes) }}",
+                "é".repeat(16)
+            )
+        );
+    }
+
+    #[test]
+    fn deltas_format_as_unified_hunks() {
+        let (block_size, old, new) = corpus().remove(0);
+        let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        assert_eq!(
+            format_unified(&diffs, 1),
+            "@@ -0,52 +0,56 @@\n \
+             =@0 40 bytes from block 0\n\
+             +not \n \
+             =@40 12 bytes from block 10\n"
+        );
+        assert_eq!(format_unified(&diffs, 0), "@@ -40,0 +40,4 @@\n+not \n");
+
+        let binary = [
+            Delta::Delete(Delete {
+                byte_index: 0,
+                bytes: 5,
+            }),
+            Delta::Add(Add {
+                byte_index: 0,
+                bytes: 3,
+                content: vec![0, b'\n', 0xff],
+            }),
+        ];
+        assert_eq!(
+            format_unified(&binary, 3),
+            "@@ -0,5 +0,3 @@\n-@0 5 bytes\n+\\x00\\n\\xff\n"
+        );
+    }
+
+    #[test]
+    fn it_emits_deltas_in_order() {
+        let (block_size, old, new) = corpus().remove(2);
+        let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        assert_eq!(Delta::validate(&diffs), Ok(()));
+        let mut new_end = 0;
+        for delta in &diffs {
+            match delta {
+                Delta::Add(add) => {
+                    assert!(add.byte_index >= new_end);
+                    new_end = add.byte_index + add.bytes;
+                }
+                Delta::Copy(copy) => new_end += copy.bytes,
+                Delta::Delete(_) => {}
+            }
+        }
+        assert_eq!(new_end, new.len() as u64);
+
+        for (block_size, old, new) in corpus() {
+            let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            assert_eq!(Delta::validate(&diffs), Ok(()));
+        }
+
+        let overlapping = vec![
+            Delta::Delete(Delete {
+                byte_index: 4,
+                bytes: 8,
+            }),
+            Delta::Delete(Delete {
+                byte_index: 8,
+                bytes: 4,
+            }),
+        ];
+        assert_eq!(
+            Delta::validate(&overlapping),
+            Err(OverlapError { index: 1 })
+        );
+    }
+
+    #[test]
+    fn it_coalesces_adjacent_deltas() {
+        let deltas = vec![
+            Delta::Add(Add {
+                byte_index: 4,
+                bytes: 3,
+                content: b"not".to_vec(),
+            }),
+            Delta::Add(Add {
+                byte_index: 7,
+                bytes: 1,
+                content: b" ".to_vec(),
+            }),
+            Delta::Delete(Delete {
+                byte_index: 8,
+                bytes: 4,
+            }),
+            Delta::Delete(Delete {
+                byte_index: 12,
+                bytes: 4,
+            }),
+            Delta::Delete(Delete {
+                byte_index: 20,
+                bytes: 4,
+            }),
+        ];
+        let coalesced = coalesce_deltas(deltas);
+        assert_eq!(coalesced.len(), 3);
+        match &coalesced[0] {
             Delta::Add(add) => {
-                assert_eq!(
-                    String::from_utf8(add.content.clone()).unwrap(),
-                    String::from(" without wands please -")
-                );
+                assert_eq!(add.byte_index, 4);
+                assert_eq!(add.bytes, 4);
+                assert_eq!(add.content, b"not ".to_vec());
             }
             _ => panic!("Should not be delete"),
         }
+        match &coalesced[1] {
+            Delta::Delete(delete) => {
+                assert_eq!(delete.byte_index, 8);
+                assert_eq!(delete.bytes, 8);
+            }
+            _ => panic!("Should not be add"),
+        }
+    }
 
-        // Second insertion content
-        match &diffs[1] {
+    #[test]
+    fn it_emits_one_add_for_a_large_insertion() {
+        let old = pseudo_random(4_000);
+        let mut new = old.clone();
+        // spans many window steps at block size 16
+        let insertion: Vec<u8> = old[..500].iter().map(|b| !b).collect();
+        new.splice(1_600..1_600, insertion.iter().cloned());
+
+        let diffs = check_diffs(16, Cursor::new(&old[..]), Cursor::new(&new[..])).unwrap();
+        let diffs = without_copies(diffs);
+        assert_eq!(diffs.len(), 1);
+        match &diffs[0] {
             Delta::Add(add) => {
-                assert_eq!(
-                    String::from_utf8(add.content.clone()).unwrap(),
-                    String::from("iculous")
-                );
+                assert_eq!(add.byte_index, 1_600);
+                assert_eq!(add.content, insertion);
             }
             _ => panic!("Should not be delete"),
         }
+    }
+
+    #[test]
+    fn it_diffs_with_rabin_karp() {
+        for (block_size, old, new) in corpus() {
+            let diffs = check_diffs_with::<Blake2b, RabinKarp>(
+                block_size,
+                Cursor::new(&old),
+                Cursor::new(&new),
+            )
+            .unwrap();
+            assert_eq!(apply_delta(&old, block_size, &diffs), new);
+        }
+    }
 
-        // Deletion bytes
-        match &diffs[2] {
-            Delta::Delete(delete) => {
-                assert_eq!(
-                    delete.bytes,
-                    String::from("dikulus").as_bytes().len() as u16
-                );
+    #[test]
+    fn it_copies_every_matched_range() {
+        for (block_size, old, new) in corpus() {
+            let diffs = check_diffs(block_size, Cursor::new(&old), Cursor::new(&new)).unwrap();
+            // the new file is exactly the added and copied bytes in order
+            let mut rebuilt = Vec::new();
+            for delta in &diffs {
+                match delta {
+                    Delta::Add(add) => rebuilt.extend_from_slice(&add.content),
+                    Delta::Copy(copy) => {
+                        let start = copy.src_byte_index as usize;
+                        rebuilt.extend_from_slice(&old[start..start + copy.bytes as usize]);
+                    }
+                    Delta::Delete(_) => {}
+                }
             }
-            _ => panic!("Should not be add"),
+            assert_eq!(rebuilt, new);
+            assert_eq!(apply_delta(&old, block_size, &diffs), new);
+        }
+    }
+
+    #[test]
+    fn it_diffs_with_crc32() {
+        for (block_size, old, new) in corpus() {
+            let diffs = DiffBuilder::<Blake2b, Crc32>::with_digest()
+                .block_size(block_size)
+                .diff(Cursor::new(&old), Cursor::new(&new))
+                .unwrap();
+            assert_eq!(apply_delta(&old, block_size, &diffs), new);
         }
     }
 }
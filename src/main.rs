@@ -0,0 +1,69 @@
+//! Command line tool producing signatures, deltas and patched files like `rdiff`
+//!
+//! ```text
+//! rolling_hash signature <oldfile> <sig>
+//! rolling_hash delta <sig> <newfile> <delta>
+//! rolling_hash patch <oldfile> <delta> <out>
+//! ```
+
+use rolling_hash::{
+    apply_delta, read_delta, read_file, recommended_block_size, write_signature_delta, Signature,
+};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, ErrorKind, Write};
+use std::process;
+
+const USAGE: &str = "usage:
+    rolling_hash signature <oldfile> <sig>
+    rolling_hash delta <sig> <newfile> <delta>
+    rolling_hash patch <oldfile> <delta> <out>";
+
+/// Block size used by every subcommand for an old file of `old_len` bytes
+///
+/// A non empty old file must hold at least one block, so it is never larger than the file.
+fn block_size(old_len: usize) -> usize {
+    recommended_block_size(old_len).min(old_len).max(1)
+}
+
+fn signature(old: &str, sig: &str) -> io::Result<()> {
+    let old = read_file(old)?;
+    let mut signature = Signature::new(block_size(old.len()));
+    signature
+        .generate(&mut Cursor::new(&old))
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+    fs::write(sig, signature.encode())
+}
+
+fn delta(sig: &str, new: &str, delta: &str) -> io::Result<()> {
+    let signature: Signature = Signature::decode(&read_file(sig)?)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, format!("{:?}", err)))?;
+    let new = File::open(new)?;
+    let mut out = BufWriter::new(File::create(delta)?);
+    write_signature_delta(&signature, new, &mut out)?;
+    out.flush()
+}
+
+fn patch(old: &str, delta: &str, out: &str) -> io::Result<()> {
+    let old = read_file(old)?;
+    let deltas = read_delta(&mut File::open(delta)?)?;
+    fs::write(out, apply_delta(&old, block_size(old.len()), &deltas))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["signature", old, sig] => signature(old, sig),
+        ["delta", sig, new, out] => delta(sig, new, out),
+        ["patch", old, delta, out] => patch(old, delta, out),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("rolling_hash: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::Cursor;
+use std::ops::Range;
+
+use crate::{check_diffs, Delta, DiffError};
+
+/// Outcome of `three_way`
+#[derive(Debug, Default, PartialEq)]
+pub struct MergeResult {
+    /// Ranges of the base file changed by both sides, in ascending order
+    pub conflicts: Vec<Range<u64>>,
+    /// `Add`s and `Delete`s of base to mine clear of any conflict
+    pub mine: Vec<Delta>,
+    /// `Add`s and `Delete`s of base to theirs clear of any conflict
+    pub theirs: Vec<Delta>,
+}
+
+/// Diffs base to mine and base to theirs and splits the changes into conflicts and clean ones
+///
+/// A change is a run of `Add`s and `Delete`s without matched bytes of the base file in
+/// between. Changes of both sides touching the same bytes of the base file conflict, an
+/// insertion conflicts with a change of the other side starting or ending at it. The clean
+/// deltas of each side keep the coordinates of that side's new file, without conflicts
+/// `apply_delta(base, block_size, &result.mine)` rebuilds mine.
+pub fn three_way(
+    base: &[u8],
+    mine: &[u8],
+    theirs: &[u8],
+    block_size: usize,
+) -> Result<MergeResult, DiffError> {
+    let mine = check_diffs(block_size, Cursor::new(base), Cursor::new(mine))?;
+    let theirs = check_diffs(block_size, Cursor::new(base), Cursor::new(theirs))?;
+    let mine_changes = changes(base.len() as u64, &mine);
+    let theirs_changes = changes(base.len() as u64, &theirs);
+
+    let mut conflicts: Vec<Range<u64>> = Vec::new();
+    let mut mine_conflicting = vec![false; mine_changes.len()];
+    let mut theirs_conflicting = vec![false; theirs_changes.len()];
+    for (i, (ours, _)) in mine_changes.iter().enumerate() {
+        for (j, (other, _)) in theirs_changes.iter().enumerate() {
+            if !overlap(ours, other) {
+                continue;
+            }
+            mine_conflicting[i] = true;
+            theirs_conflicting[j] = true;
+            let range = ours.start.min(other.start)..ours.end.max(other.end);
+            conflicts.push(range);
+        }
+    }
+    conflicts.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in conflicts {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    Ok(MergeResult {
+        conflicts: merged,
+        mine: clean(mine, &mine_changes, &mine_conflicting),
+        theirs: clean(theirs, &theirs_changes, &theirs_conflicting),
+    })
+}
+
+/// Ranges of the base file changed by `deltas`, with the indices of their deltas
+///
+/// Tracks the base file the way `apply_delta` does to place `Add`s, which are in
+/// coordinates of the new file.
+fn changes(base_len: u64, deltas: &[Delta]) -> Vec<(Range<u64>, Vec<usize>)> {
+    let mut changes: Vec<(Range<u64>, Vec<usize>)> = Vec::new();
+    let mut old_index = 0;
+    let mut new_len = 0;
+    for (index, delta) in deltas.iter().enumerate() {
+        let range = match delta {
+            Delta::Add(add) => {
+                let at = base_len.min(old_index + add.byte_index.saturating_sub(new_len));
+                new_len += at - old_index + add.bytes;
+                old_index = at;
+                at..at
+            }
+            Delta::Delete(delete) => {
+                let start = base_len.min(delete.byte_index.max(old_index));
+                new_len += start - old_index;
+                old_index = base_len.min(start + delete.bytes);
+                start..old_index
+            }
+            Delta::Copy(copy) => {
+                let end = base_len.min(copy.src_byte_index + copy.bytes);
+                new_len += copy.bytes;
+                old_index = old_index.max(end);
+                continue;
+            }
+        };
+        match changes.last_mut() {
+            Some((last, indices)) if range.start <= last.end => {
+                last.end = last.end.max(range.end);
+                indices.push(index);
+            }
+            _ => changes.push((range, vec![index])),
+        }
+    }
+    changes
+}
+
+fn overlap(a: &Range<u64>, b: &Range<u64>) -> bool {
+    if a.is_empty() || b.is_empty() {
+        a.start <= b.end && b.start <= a.end
+    } else {
+        a.start < b.end && b.start < a.end
+    }
+}
+
+fn clean(
+    deltas: Vec<Delta>,
+    changes: &[(Range<u64>, Vec<usize>)],
+    conflicting: &[bool],
+) -> Vec<Delta> {
+    let mut keep = vec![false; deltas.len()];
+    for ((_, indices), conflicting) in changes.iter().zip(conflicting) {
+        if !conflicting {
+            for &index in indices {
+                keep[index] = true;
+            }
+        }
+    }
+    deltas
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(delta, _)| delta)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply_delta;
+    use crate::tests::pseudo_random;
+
+    #[test]
+    fn edits_far_apart_merge_cleanly() {
+        let base = pseudo_random(4096);
+        let mut mine = base.clone();
+        mine.splice(1000..1003, b"Mischief managed".iter().cloned());
+        let mut theirs = base.clone();
+        theirs.splice(3000..3010, b"Nox".iter().cloned());
+
+        let result = three_way(&base, &mine, &theirs, 16).unwrap();
+        assert!(result.conflicts.is_empty());
+        assert_eq!(apply_delta(&base, 16, &result.mine), mine);
+        assert_eq!(apply_delta(&base, 16, &result.theirs), theirs);
+    }
+
+    #[test]
+    fn edits_of_the_same_bytes_conflict() {
+        let base = pseudo_random(4096);
+        let mut mine = base.clone();
+        mine.splice(2000..2003, b"Lumos".iter().cloned());
+        mine.splice(1000..1003, b"Mischief managed".iter().cloned());
+        let mut theirs = base.clone();
+        theirs.splice(3000..3010, b"Alohomora".iter().cloned());
+        theirs.splice(2001..2002, b"Nox".iter().cloned());
+
+        let result = three_way(&base, &mine, &theirs, 16).unwrap();
+        assert_eq!(result.conflicts.len(), 1);
+        let conflict = &result.conflicts[0];
+        assert!(conflict.start <= 2000 && 2003 <= conflict.end);
+        // only the edit at 1000 of mine and at 3000 of theirs is left
+        assert!(!result.mine.is_empty());
+        assert!(result.mine.iter().all(|delta| delta.byte_index() < 1100));
+        assert!(!result.theirs.is_empty());
+        assert!(result.theirs.iter().all(|delta| delta.byte_index() > 2900));
+    }
+}
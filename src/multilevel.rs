@@ -0,0 +1,241 @@
+use std::io::Cursor;
+use std::ops::Range;
+
+use crate::{check_diffs, delta_spans, Delta, DiffError};
+
+/// Diffs at `coarse` blocks, then diffs every changed region again at `fine` blocks
+///
+/// Matching large blocks is fast and finds the unchanged regions, the small blocks then
+/// narrow each changed region down to the bytes that actually changed. Regions whose part
+/// of the old file is shorter than a fine block are kept as the coarse diff found them.
+/// The `block_index` of a refined `Copy` counts blocks of `fine` bytes from the start of
+/// the old file, so `coarse` should be a multiple of `fine`.
+pub fn diff_multilevel(
+    old: &[u8],
+    new: &[u8],
+    coarse: usize,
+    fine: usize,
+) -> Result<Vec<Delta>, DiffError> {
+    if fine == 0 {
+        return Err(DiffError::InvalidBlockSize);
+    }
+    let deltas = check_diffs(coarse, Cursor::new(old), Cursor::new(new))?;
+    let mut refined = Vec::with_capacity(deltas.len());
+    for (region, deltas) in regions(deltas) {
+        let region = match region {
+            Some(region) => region,
+            None => {
+                refined.extend(deltas);
+                continue;
+            }
+        };
+        let old_part = &old[region.old.start as usize..region.old.end as usize];
+        let new_part = &new[region.new.start as usize..region.new.end as usize];
+        match check_diffs(fine, Cursor::new(old_part), Cursor::new(new_part)) {
+            Ok(fine_deltas) => {
+                refined.extend(
+                    fine_deltas
+                        .into_iter()
+                        .map(|delta| match shifted(delta, &region) {
+                            Delta::Copy(mut copy) => {
+                                copy.block_index = copy.src_byte_index / fine as u64;
+                                Delta::Copy(copy)
+                            }
+                            delta => delta,
+                        }),
+                )
+            }
+            Err(DiffError::InputTooSmall { .. }) => refined.extend(deltas),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(crate::coalesce_deltas(refined))
+}
+
+/// Diffs every range of the files in `regions` at its own block size, e.g. a header and a body
+///
+/// The ranges are of both files, clamped to their lengths, and taken in order. A range
+/// overlapping the one before starts where that one ends, bytes in no range are left out
+/// of the deltas like bytes skipped by `DiffBuilder::skip_prefix`. The deltas of a range
+/// are in coordinates of the whole files, so `apply_delta` on the whole old file copies
+/// bytes in no range like the other bytes no delta covers. Only the `block_index` of a
+/// `Copy` counts blocks from the start of its range. Fails like `check_diffs` if a range
+/// fails.
+pub fn check_diffs_regions(
+    old: &[u8],
+    new: &[u8],
+    regions: &[(Range<usize>, usize)],
+) -> Result<Vec<Delta>, DiffError> {
+    let mut deltas = Vec::new();
+    let mut covered = 0;
+    for (range, block_size) in regions {
+        let start = range.start.max(covered);
+        let end = range.end.max(start);
+        covered = end;
+        let clamped = |file: &[u8]| file.len().min(start)..file.len().min(end);
+        let region = Region {
+            old: as_u64(clamped(old)),
+            new: as_u64(clamped(new)),
+        };
+        let region_deltas = check_diffs(
+            *block_size,
+            Cursor::new(&old[clamped(old)]),
+            Cursor::new(&new[clamped(new)]),
+        )?;
+        deltas.extend(
+            region_deltas
+                .into_iter()
+                .map(|delta| shifted(delta, &region)),
+        );
+    }
+    Ok(deltas)
+}
+
+fn as_u64(range: Range<usize>) -> Range<u64> {
+    range.start as u64..range.end as u64
+}
+
+/// The bytes of both files a run of `Add`s and `Delete`s changes
+struct Region {
+    old: Range<u64>,
+    new: Range<u64>,
+}
+
+/// Splits `deltas` into runs of changes, each with its region, and `Copy`s without one
+fn regions(deltas: Vec<Delta>) -> Vec<(Option<Region>, Vec<Delta>)> {
+    let spans = delta_spans(&deltas);
+    let mut regions: Vec<(Option<Region>, Vec<Delta>)> = Vec::new();
+    for (delta, (old, new)) in deltas.into_iter().zip(spans) {
+        if let Delta::Copy(_) = delta {
+            regions.push((None, vec![delta]));
+            continue;
+        }
+        match regions.last_mut() {
+            Some((Some(region), deltas))
+                if region.old.end == old.start && region.new.end == new.start =>
+            {
+                region.old.end = old.end;
+                region.new.end = new.end;
+                deltas.push(delta);
+            }
+            _ => regions.push((Some(Region { old, new }), vec![delta])),
+        }
+    }
+    regions
+}
+
+/// `delta` of a diff between the parts of the files in `region`, moved to the whole files
+fn shifted(delta: Delta, region: &Region) -> Delta {
+    match delta {
+        Delta::Add(mut add) => {
+            add.byte_index += region.new.start;
+            Delta::Add(add)
+        }
+        Delta::Delete(mut delete) => {
+            delete.byte_index += region.old.start;
+            Delta::Delete(delete)
+        }
+        Delta::Copy(mut copy) => {
+            copy.src_byte_index += region.old.start;
+            Delta::Copy(copy)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::pseudo_random;
+    use crate::{apply_delta, DiffStats};
+
+    #[test]
+    fn changed_regions_are_refined() {
+        let old = pseudo_random(64 * 1_024);
+        let mut new = old.clone();
+        new.splice(30_000..30_003, b"Nox".iter().cloned());
+        new.splice(50_000..50_000, b"Lumos".iter().cloned());
+
+        let coarse = check_diffs(4_096, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        let fine = check_diffs(64, Cursor::new(&old), Cursor::new(&new)).unwrap();
+        let multilevel = diff_multilevel(&old, &new, 4_096, 64).unwrap();
+        assert_eq!(apply_delta(&old, 64, &multilevel), new);
+
+        let coarse = DiffStats::new(&coarse);
+        let fine = DiffStats::new(&fine);
+        let multilevel = DiffStats::new(&multilevel);
+        // as tight as diffing at the fine block size all along
+        assert!(multilevel.literal_bytes * 16 < coarse.literal_bytes);
+        assert_eq!(multilevel.literal_bytes, fine.literal_bytes);
+        assert!(multilevel.deltas <= fine.deltas + 4);
+    }
+
+    #[test]
+    fn regions_are_diffed_at_their_block_size() {
+        let old = pseudo_random(512 + 4_096);
+        let mut new = old.clone();
+        new.splice(2_000..2_000, b"Lumos".iter().cloned());
+        new[100] ^= 1;
+        let regions = [(0..512, 32), (512..usize::MAX, 256)];
+        let deltas = check_diffs_regions(&old, &new, &regions).unwrap();
+        assert_eq!(apply_delta(&old, 32, &deltas), new);
+
+        let header_changes: Vec<&Delta> = deltas
+            .iter()
+            .filter(|delta| !matches!(delta, Delta::Copy(_)) && delta.byte_index() < 512)
+            .collect();
+        // the byte changed in the header is replaced by its block of 32 bytes
+        assert!(matches!(
+            header_changes[..],
+            [Delta::Add(add), Delta::Delete(delete)] | [Delta::Delete(delete), Delta::Add(add)]
+                if add.bytes() == 32 && delete.byte_index() == 96 && delete.bytes() == 32
+        ));
+        for delta in &deltas {
+            if let Delta::Copy(copy) = delta {
+                match copy.src_byte_index() < 512 {
+                    true => assert_eq!(copy.src_byte_index() % 32, 0),
+                    false => assert_eq!((copy.src_byte_index() - 512) % 256, 0),
+                }
+            }
+        }
+        // the body is diffed at 256 bytes, so the insertion takes a block of it
+        let body_added: u64 = deltas
+            .iter()
+            .filter(|delta| delta.byte_index() >= 512)
+            .map(|delta| match delta {
+                Delta::Add(add) => add.bytes(),
+                _ => 0,
+            })
+            .sum();
+        assert!(body_added >= 256);
+    }
+
+    #[test]
+    fn unchanged_regions_and_gaps_are_kept() {
+        let old = pseudo_random(512 + 4_096);
+        let mut new = old.clone();
+        new.splice(2_000..2_000, b"Lumos".iter().cloned());
+        let regions = [(0..512, 32), (512..usize::MAX, 256)];
+        let deltas = check_diffs_regions(&old, &new, &regions).unwrap();
+        assert!(deltas.iter().all(|delta| delta.byte_index() >= 512));
+        assert!(matches!(deltas[0], Delta::Copy(_)));
+        assert_eq!(apply_delta(&old, 32, &deltas), new);
+
+        // the first 100 bytes are in no range
+        let regions = [(100..512, 32), (512..usize::MAX, 256)];
+        let deltas = check_diffs_regions(&old, &new, &regions).unwrap();
+        assert_eq!(apply_delta(&old, 32, &deltas), new);
+    }
+
+    #[test]
+    fn regions_shorter_than_a_fine_block_stay_coarse() {
+        let old = pseudo_random(1_024);
+        let mut new = old.clone();
+        new.extend_from_slice(b"Nox");
+        let multilevel = diff_multilevel(&old, &new, 256, 64).unwrap();
+        assert_eq!(apply_delta(&old, 64, &multilevel), new);
+        assert_eq!(
+            diff_multilevel(&old, &new, 256, 0).unwrap_err(),
+            DiffError::InvalidBlockSize
+        );
+    }
+}
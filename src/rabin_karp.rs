@@ -0,0 +1,92 @@
+use crate::rollsum::{Error, RollingHash};
+use core::num::Wrapping;
+
+/// Multiplier of the polynomial, odd so it is invertible modulo 2^32
+const BASE: Wrapping<u32> = Wrapping(0x0100_0193);
+
+/// Polynomial rolling hash, the digest is `b0 * BASE^(n-1) + b1 * BASE^(n-2) + ... + bn-1`
+///
+/// Every byte position gets its own weight so it spreads binary data better than the
+/// Adler sums of `Rollsum`, which only weigh bytes linearly by position.
+#[derive(Debug)]
+pub struct RabinKarp {
+    hash: Wrapping<u32>,
+    /// BASE^(block_size - 1), the weight of the byte leaving the block
+    leading_weight: Wrapping<u32>,
+    block_size: usize,
+}
+
+impl RabinKarp {
+    fn weight(block_size: usize) -> Wrapping<u32> {
+        Wrapping(BASE.0.wrapping_pow(block_size.saturating_sub(1) as u32))
+    }
+}
+
+impl RollingHash for RabinKarp {
+    fn new(buf: &[u8]) -> Self {
+        let mut hash = Wrapping(0);
+        for byte in buf {
+            hash = hash * BASE + Wrapping(*byte as u32);
+        }
+        Self {
+            hash,
+            leading_weight: Self::weight(buf.len()),
+            block_size: buf.len(),
+        }
+    }
+
+    fn digest(&self) -> u32 {
+        self.hash.0
+    }
+
+    fn roll_hash(&mut self, new: Option<u8>, old: u8) {
+        self.hash -= self.leading_weight * Wrapping(old as u32);
+        if let Some(new) = new {
+            self.hash = self.hash * BASE + Wrapping(new as u32);
+        } else {
+            self.block_size -= 1;
+            self.leading_weight = Self::weight(self.block_size);
+        }
+    }
+
+    fn batch_roll(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if buf.len() > self.block_size {
+            return Err(Error::BatchRollError);
+        }
+        *self = Self::new(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_matches_fresh_hash() {
+        let buf: Vec<u8> = (0..40u32).map(|i| ((i * 97 + 13) % 256) as u8).collect();
+        let mut rk = RabinKarp::new(&buf[..16]);
+        for start in 1..=20 {
+            rk.roll_hash(Some(buf[start + 15]), buf[start - 1]);
+            assert_eq!(
+                rk.digest(),
+                RabinKarp::new(&buf[start..start + 16]).digest()
+            );
+        }
+        rk.roll_hash(None, buf[20]);
+        assert_eq!(rk.digest(), RabinKarp::new(&buf[21..36]).digest());
+
+        // into a short final block
+        rk.batch_roll(&buf[36..]).unwrap();
+        assert_eq!(rk.digest(), RabinKarp::new(&buf[36..]).digest());
+        assert!(rk.batch_roll(&buf[..16]).is_err());
+    }
+
+    #[test]
+    fn byte_order_changes_digest() {
+        assert_ne!(
+            RabinKarp::new(&[1, 2, 3, 4]).digest(),
+            RabinKarp::new(&[2, 1, 3, 4]).digest()
+        );
+    }
+}
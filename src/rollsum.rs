@@ -1,15 +1,35 @@
-use std::num::Wrapping;
+use core::num::Wrapping;
 
-/// This rolling sum uses Adler-32 checksum which is used in rsync
-///
+/// Modulus of the reference Adler-32, the largest prime below 2^16
+pub const ADLER_MODULUS: u32 = 65521;
+
+/// A weak checksum over a block that can be moved along a buffer byte by byte
+pub trait RollingHash {
+    /// Checksum of the initial block, later windows are assumed to be of the same length
+    fn new(buf: &[u8]) -> Self;
+    /// Current digest at this block
+    fn digest(&self) -> u32;
+    /// Drops `old` from the front of the block and appends `new` if any
+    fn roll_hash(&mut self, new: Option<u8>, old: u8);
+    /// Replaces the whole block with `buf`, later windows are then of its length
+    ///
+    /// `buf` may be shorter than the block, e.g. a short final block, but not longer.
+    fn batch_roll(&mut self, buf: &[u8]) -> Result<(), Error>;
+}
 
+/// This rolling sum uses Adler-32 checksum which is used in rsync
+#[derive(Debug)]
 pub struct Rollsum {
     /// sum of bytes - wrapped round max
     pub s: Wrapping<u32>,
     /// sum of sum of bytes - wrapped round max
     pub ss: Wrapping<u32>,
-    /// the total size in the block
+    /// the number of bytes summed, the length of every window rolled to
     pub block_size: usize,
+    /// sums are reduced by this modulus instead of wrapping, if set
+    modulus: Option<u32>,
+    /// added to every byte before it is summed
+    char_offset: u32,
 }
 
 #[derive(Debug)]
@@ -17,59 +37,201 @@ pub enum Error {
     BatchRollError,
 }
 
+/// Parameters of a `Rollsum`, e.g. rsync's exact ones instead of the fast wrapping sums
+///
+/// `RollsumBuilder::new().modulus(Some(ADLER_MODULUS)).char_offset(31).build(block)`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RollsumBuilder {
+    modulus: Option<u32>,
+    char_offset: u32,
+}
+
+impl RollsumBuilder {
+    /// Wrapping sums without an offset, the same as `Rollsum::new`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reduces the sums by `modulus` instead of wrapping them, a modulus of 0 wraps
+    pub fn modulus(mut self, modulus: Option<u32>) -> Self {
+        self.modulus = modulus.filter(|modulus| *modulus > 0);
+        self
+    }
+
+    /// Adds `char_offset` to every byte before summing it
+    ///
+    /// rsync uses an offset so runs of zero bytes of different lengths sum differently.
+    pub fn char_offset(mut self, char_offset: u32) -> Self {
+        self.char_offset = char_offset;
+        self
+    }
+
+    /// Sums of `block` with these parameters
+    pub fn build(&self, block: &[u8]) -> Rollsum {
+        let mut rs = Rollsum {
+            s: Wrapping(0),
+            ss: Wrapping(0),
+            block_size: block.len(),
+            modulus: self.modulus,
+            char_offset: self.char_offset,
+        };
+        for byte in block {
+            rs.push(*byte);
+        }
+        rs
+    }
+}
+
 impl Rollsum {
-    /// Warning: Assumes that the initial block has the exact blocksize required
+    /// Sums of `_buf`, `block_size` is the number of bytes summed
+    ///
+    /// Rolling assumes every later window has as many bytes, so a window primed from a short
+    /// slice rolls on as a window of that short length.
     pub fn new(_buf: &[u8]) -> Self {
-        let mut s = Wrapping(0 as u32);
-        let mut ss = Wrapping(0 as u32);
-        let mut block_size = 0usize;
-        for byte in _buf {
-            s += Wrapping(*byte as u32);
-            ss += s;
-            block_size += 1;
+        RollsumBuilder::new().build(_buf)
+    }
+
+    /// Same as `new` but the sums are reduced modulo 65521 like the reference Adler-32
+    ///
+    /// This is slower than the wrapping sums but spreads weak checksums better, so highly
+    /// repetitive inputs need fewer strong hash checks.
+    pub fn new_adler32(_buf: &[u8]) -> Self {
+        RollsumBuilder::new()
+            .modulus(Some(ADLER_MODULUS))
+            .build(_buf)
+    }
+
+    /// What `byte` adds to the sums, with the offset and reduced by the modulus
+    fn value(&self, byte: u8) -> u32 {
+        let value = (byte as u32).wrapping_add(self.char_offset);
+        self.modulus.map_or(value, |modulus| value % modulus)
+    }
+
+    /// Adds a byte at the end of the sums
+    fn push(&mut self, byte: u8) {
+        let value = self.value(byte);
+        match self.modulus {
+            None => {
+                self.s += Wrapping(value);
+                self.ss += self.s;
+            }
+            Some(modulus) => {
+                self.s.0 = ((self.s.0 as u64 + value as u64) % modulus as u64) as u32;
+                self.ss.0 = ((self.ss.0 as u64 + self.s.0 as u64) % modulus as u64) as u32;
+            }
         }
-        Self { s, ss, block_size }
     }
 
     /// Current digest at this block
+    ///
+    /// The low 16 bits of `ss` are the high half of the digest, `s` is or-ed into it whole:
+    /// with wrapping sums its bits above the low 16 mix with those of `ss`, reduced by a
+    /// modulus below 2^16 like Adler-32 they are the low half.
     pub fn digest(&self) -> u32 {
         self.ss.0 << 16 | self.s.0
     }
 
+    /// The sum of the bytes `s`, the sum of the prefix sums `ss` and the bytes in the window
+    ///
+    /// Bytes are summed with the char offset and modulus of the builder, see `digest` for
+    /// how the sums make up the digest.
+    pub fn state(&self) -> (u32, u32, usize) {
+        (self.s.0, self.ss.0, self.block_size)
+    }
+
     /// Prepares Rollsum for the next byte, get the hash with digest()
     ///
     /// This is useful for checking quickly if the new file might have the same block
+    ///
+    /// Removing `old_byte` takes it out of `s` once and out of `ss` once per prefix sum it was
+    /// part of (block_size times), the new byte then adds the new `s` as the last prefix sum.
     pub fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
-        self.s -= Wrapping(old_byte as u32);
-        self.ss -= Wrapping(self.block_size as u32) * Wrapping(old_byte as u32);
+        let old = self.value(old_byte);
+        match self.modulus {
+            None => {
+                self.s -= Wrapping(old);
+                self.ss -= Wrapping(self.block_size as u32) * Wrapping(old);
+            }
+            Some(modulus) => {
+                // add the modulus before subtracting so the sums never go below zero
+                let modulus = modulus as u64;
+                let removed = (self.block_size as u64 % modulus) * old as u64 % modulus;
+                self.s.0 = ((self.s.0 as u64 + modulus - old as u64) % modulus) as u32;
+                self.ss.0 = ((self.ss.0 as u64 + modulus - removed) % modulus) as u32;
+            }
+        }
         if let Some(new_byte) = new_byte {
-            self.s += Wrapping(new_byte as u32);
-            self.ss += self.s;
+            self.push(new_byte);
         } else {
             self.block_size -= 1
         }
     }
 
-    /// Prepare Rollsum for a next set of bytes of len eq block_size
+    /// Undoes `roll_hash(Some(old_trailing), new_leading)`, moving the block back a byte
+    ///
+    /// `new_leading` enters at the front again and `old_trailing` leaves from the end, this is
+    /// useful for extending a match backward.
+    pub fn roll_back(&mut self, new_leading: u8, old_trailing: u8) {
+        let leading = self.value(new_leading);
+        let trailing = self.value(old_trailing);
+        match self.modulus {
+            None => {
+                self.ss -= self.s;
+                self.ss += Wrapping(self.block_size as u32) * Wrapping(leading);
+                self.s -= Wrapping(trailing);
+                self.s += Wrapping(leading);
+            }
+            Some(modulus) => {
+                let modulus = modulus as u64;
+                let added = (self.block_size as u64 % modulus) * leading as u64 % modulus;
+                self.ss.0 =
+                    ((self.ss.0 as u64 + modulus - self.s.0 as u64 + added) % modulus) as u32;
+                self.s.0 = ((self.s.0 as u64 + modulus - trailing as u64 + leading as u64)
+                    % modulus) as u32;
+            }
+        }
+    }
+
+    /// Prepare Rollsum for a next set of bytes of at most block_size
     ///
-    /// Useful for creating signature and roll forward a whole block after matched
+    /// Useful for creating signature and roll forward a whole block after matched. A shorter
+    /// buffer, like a short final block, becomes the new block_size.
     pub fn batch_roll(&mut self, buffer: &[u8]) -> Result<(), Error> {
-        if buffer.len() != self.block_size {
+        if buffer.len() > self.block_size {
             return Err(Error::BatchRollError);
         }
+        self.block_size = buffer.len();
         self.s = Wrapping(0);
         self.ss = Wrapping(0);
         for byte in buffer {
-            self.s += Wrapping(*byte as u32);
-            self.ss += self.s;
+            self.push(*byte);
         }
         Ok(())
     }
 }
 
+impl RollingHash for Rollsum {
+    fn new(buf: &[u8]) -> Self {
+        Rollsum::new(buf)
+    }
+
+    fn digest(&self) -> u32 {
+        Rollsum::digest(self)
+    }
+
+    fn roll_hash(&mut self, new: Option<u8>, old: u8) {
+        Rollsum::roll_hash(self, new, old)
+    }
+
+    fn batch_roll(&mut self, buf: &[u8]) -> Result<(), Error> {
+        Rollsum::batch_roll(self, buf)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
 
     #[test]
     fn initial_rollsum_works() {
@@ -90,6 +252,22 @@ mod tests {
         assert_eq!(rs.digest(), 50 << 16 | 20);
     }
 
+    #[test]
+    fn state_is_the_sums_of_the_window() {
+        let mut rs = Rollsum::new(&[1, 2, 3, 4, 5]);
+        rs.roll_hash(Some(6), 1);
+        rs.roll_hash(Some(7), 2);
+        // 3 + 4 + 5 + 6 + 7 and 5 * 3 + 4 * 4 + 3 * 5 + 2 * 6 + 7
+        assert_eq!(rs.state(), (25, 65, 5));
+        rs.roll_hash(None, 3);
+        // 4 * 4 + 3 * 5 + 2 * 6 + 7
+        assert_eq!(rs.state(), (22, 50, 4));
+        assert_eq!(rs.digest(), 50 << 16 | 22);
+
+        let offset = RollsumBuilder::new().char_offset(31).build(&[1, 2]);
+        assert_eq!(offset.state(), (32 + 33, 2 * 32 + 33, 2));
+    }
+
     #[test]
     fn roll_forward_empty_works() {
         let v = vec![1, 2, 3, 4, 5];
@@ -99,4 +277,199 @@ mod tests {
         assert_eq!(rs.ss.0, 30);
         assert_eq!(rs.digest(), 30 << 16 | 14);
     }
+
+    #[test]
+    fn rolling_matches_fresh_rollsum() {
+        let buf: Vec<u8> = (0..40u32).map(|i| ((i * 97 + 13) % 256) as u8).collect();
+        let mut rs = Rollsum::new(&buf[..16]);
+        for start in 1..=20 {
+            rs.roll_hash(Some(buf[start + 15]), buf[start - 1]);
+            let fresh = Rollsum::new(&buf[start..start + 16]);
+            assert_eq!(rs.s, fresh.s);
+            assert_eq!(rs.ss, fresh.ss);
+            assert_eq!(rs.digest(), fresh.digest());
+        }
+
+        let mut batched = Rollsum::new(&buf[..16]);
+        batched.batch_roll(&buf[20..36]).unwrap();
+        assert_eq!(batched.digest(), rs.digest());
+    }
+
+    #[test]
+    fn rolling_back_restores_the_digest() {
+        let buf: Vec<u8> = (0..40u32).map(|i| ((i * 97 + 13) % 256) as u8).collect();
+        for mut rs in [Rollsum::new(&buf[..16]), Rollsum::new_adler32(&buf[..16])] {
+            let digests: Vec<u32> = (0..24)
+                .map(|start| {
+                    let digest = rs.digest();
+                    rs.roll_hash(Some(buf[start + 16]), buf[start]);
+                    digest
+                })
+                .collect();
+            for start in (0..24).rev() {
+                rs.roll_back(buf[start], buf[start + 16]);
+                assert_eq!(rs.digest(), digests[start]);
+            }
+        }
+    }
+
+    #[test]
+    fn short_slices_roll_as_short_windows() {
+        let buf: Vec<u8> = (0..40u32).map(|i| ((i * 89 + 7) % 256) as u8).collect();
+        // primed from 5 bytes of what was meant as a window of 16
+        let mut rs = Rollsum::new(&buf[..5]);
+        assert_eq!(rs.block_size, 5);
+        assert_eq!(rs.digest(), Rollsum::new(&buf[..5]).digest());
+        for start in 1..=20 {
+            rs.roll_hash(Some(buf[start + 4]), buf[start - 1]);
+            assert_eq!(rs.digest(), Rollsum::new(&buf[start..start + 5]).digest());
+        }
+        assert!(rs.batch_roll(&buf[..16]).is_err());
+    }
+
+    #[test]
+    fn batch_rolling_into_a_short_final_block() {
+        let buf: Vec<u8> = (0..21u32).map(|i| ((i * 97 + 13) % 256) as u8).collect();
+        let mut rs = Rollsum::new(&buf[..16]);
+        rs.batch_roll(&buf[16..]).unwrap();
+        assert_eq!(rs.block_size, 5);
+        assert_eq!(rs.digest(), Rollsum::new(&buf[16..]).digest());
+        rs.roll_hash(None, buf[16]);
+        assert_eq!(rs.digest(), Rollsum::new(&buf[17..]).digest());
+
+        let mut adler32 = Rollsum::new_adler32(&buf[..16]);
+        adler32.batch_roll(&buf[16..]).unwrap();
+        assert_eq!(adler32.s, Rollsum::new_adler32(&buf[16..]).s);
+        assert!(adler32.batch_roll(&buf).is_err());
+    }
+
+    #[test]
+    fn char_offsets_change_the_digest() {
+        let zeros = [0u8; 16];
+        // without an offset runs of zeros all sum to 0
+        assert_eq!(
+            Rollsum::new(&zeros[..4]).digest(),
+            Rollsum::new(&zeros).digest()
+        );
+        let offset = RollsumBuilder::new().char_offset(31);
+        assert_ne!(
+            offset.build(&zeros[..4]).digest(),
+            offset.build(&zeros).digest()
+        );
+
+        let buf: Vec<u8> = (0..40u32).map(|i| ((i * 97 + 13) % 256) as u8).collect();
+        assert_eq!(
+            RollsumBuilder::new().build(&buf).digest(),
+            Rollsum::new(&buf).digest()
+        );
+        assert_eq!(
+            RollsumBuilder::new()
+                .modulus(Some(ADLER_MODULUS))
+                .build(&buf)
+                .digest(),
+            Rollsum::new_adler32(&buf).digest()
+        );
+        assert_ne!(offset.build(&buf).digest(), Rollsum::new(&buf).digest());
+    }
+
+    #[test]
+    fn rolling_matches_batching_for_every_builder() {
+        let buf: Vec<u8> = (0..600u32).map(|i| 255 - ((i * 31) % 11) as u8).collect();
+        let builders = [
+            RollsumBuilder::new(),
+            RollsumBuilder::new().char_offset(31),
+            RollsumBuilder::new().modulus(Some(ADLER_MODULUS)),
+            RollsumBuilder::new()
+                .modulus(Some(ADLER_MODULUS))
+                .char_offset(31),
+            // a modulus beyond 2^16 and an offset close to wrapping
+            RollsumBuilder::new()
+                .modulus(Some(u32::MAX - 4))
+                .char_offset(u32::MAX - 100),
+        ];
+        for builder in builders.iter() {
+            let mut rs = builder.build(&buf[..64]);
+            for start in 1..=100 {
+                rs.roll_hash(Some(buf[start + 63]), buf[start - 1]);
+                let mut batched = builder.build(&buf[..64]);
+                batched.batch_roll(&buf[start..start + 64]).unwrap();
+                assert_eq!(rs.digest(), batched.digest());
+                assert_eq!(rs.digest(), builder.build(&buf[start..start + 64]).digest());
+            }
+            for start in (0..100).rev() {
+                rs.roll_back(buf[start], buf[start + 64]);
+                assert_eq!(rs.digest(), builder.build(&buf[start..start + 64]).digest());
+            }
+        }
+    }
+
+    #[test]
+    fn rolling_matches_fresh_rollsums_on_random_input() {
+        let mut seed = 1_234u32;
+        let mut next = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            seed >> 8
+        };
+        for _ in 0..200 {
+            let len = 1 + next() as usize % 300;
+            let window = 1 + next() as usize % len;
+            let buf: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let builder = match next() % 3 {
+                0 => RollsumBuilder::new(),
+                1 => RollsumBuilder::new().modulus(Some(ADLER_MODULUS)),
+                _ => RollsumBuilder::new().char_offset(next()),
+            };
+            let mut rs = builder.build(&buf[..window]);
+            for start in 1..=len - window {
+                rs.roll_hash(Some(buf[start + window - 1]), buf[start - 1]);
+                let fresh = builder.build(&buf[start..start + window]);
+                assert_eq!(rs.digest(), fresh.digest(), "{len} {window} {start}");
+            }
+            // the window shrinks once the buffer runs out
+            for start in len - window + 1..len {
+                rs.roll_hash(None, buf[start - 1]);
+                assert_eq!(rs.digest(), builder.build(&buf[start..]).digest());
+            }
+        }
+    }
+
+    #[test]
+    fn adler32_rolling_matches_fresh_rollsum() {
+        let buf: Vec<u8> = (0..600u32).map(|i| 255 - (i % 7) as u8).collect();
+        let mut rs = Rollsum::new_adler32(&buf[..512]);
+        for start in 1..=20 {
+            rs.roll_hash(Some(buf[start + 511]), buf[start - 1]);
+            let fresh = Rollsum::new_adler32(&buf[start..start + 512]);
+            assert_eq!(rs.s, fresh.s);
+            assert_eq!(rs.ss, fresh.ss);
+        }
+        assert!(rs.s.0 < ADLER_MODULUS && rs.ss.0 < ADLER_MODULUS);
+    }
+
+    #[test]
+    fn adler32_has_fewer_collisions_on_repetitive_input() {
+        // long stretches of the same few high bytes, like padding or images
+        let mut seed = 7u32;
+        let buf: Vec<u8> = (0..20_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                0xff - ((seed >> 16) % 3) as u8
+            })
+            .collect();
+        let block_size = 2_048;
+        let collisions = |mut rs: Rollsum| {
+            let mut windows = HashSet::new();
+            let mut digests = HashSet::new();
+            for start in 0..buf.len() - block_size {
+                if windows.insert(&buf[start..start + block_size]) {
+                    digests.insert(rs.digest());
+                }
+                rs.roll_hash(Some(buf[start + block_size]), buf[start]);
+            }
+            windows.len() - digests.len()
+        };
+        let wrapping = collisions(Rollsum::new(&buf[..block_size]));
+        let adler32 = collisions(Rollsum::new_adler32(&buf[..block_size]));
+        assert!(adler32 * 2 < wrapping);
+    }
 }
@@ -1,76 +1,804 @@
-use crate::rollsum::Rollsum;
+use crate::chunking::ContentDefinedChunking;
+use crate::rollsum::{RollingHash, Rollsum};
+use alloc::borrow::Cow;
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, Range};
+#[cfg(feature = "std")]
 use std::collections::HashMap;
+#[cfg(feature = "std")]
 use std::io::Read;
+#[cfg(feature = "std")]
+use std::thread;
 
-use crate::{Blake2b, Digest};
+#[cfg(not(feature = "std"))]
+use crate::Read;
+use crate::{fill_block, hashes_equal, Blake2b, DiffError, Digest};
 
-/// Basic structure containing a file signature
+/// Maps a block to the bytes it is hashed as, see `Signature::with_normalizer`
+pub type Normalizer = fn(&[u8]) -> Vec<u8>;
+
+/// Blocks by weak checksum, without `std` a `BTreeMap` as there is no `HashMap`
+#[cfg(feature = "std")]
+type BlockMap = HashMap<u32, Vec<BlockHash>>;
+#[cfg(not(feature = "std"))]
+type BlockMap = BTreeMap<u32, Vec<BlockHash>>;
+
+/// Basic structure containing a file signature, strong hashes are computed with `D`
+/// and weak checksums with `R`
 #[derive(Debug)]
-pub struct Signature {
+pub struct Signature<D: Digest = Blake2b, R: RollingHash = Rollsum> {
     /// key: checksum | value: all checksum collided strong hash
-    chunk_hashes: HashMap<u32, Vec<BlockHash>>,
+    chunk_hashes: BlockMap,
     block_size: usize,
-    blocks: u16,
-    file_size: u16,
+    blocks: u64,
+    file_size: u64,
+    /// bytes of the strong hash kept per block
+    strong_hash_len: usize,
+    /// blocks are cut by content instead of every block_size bytes, if set
+    chunking: Option<ContentDefinedChunking>,
+    /// first byte of every block when cut by content
+    block_offsets: Vec<u64>,
+    /// bytes of the short last block, hashed again once `append` completes it
+    tail: Vec<u8>,
+    /// mixed into the weak checksums, 0 if unseeded
+    seed: u64,
+    /// every byte is replaced by its entry before the weak checksum, if seeded
+    byte_map: Option<[u8; 256]>,
+    /// the digest and rolling hash the signature was generated with, see `algorithm_id`
+    algorithm_id: u32,
+    /// applied to blocks before they are hashed, if set
+    normalize: Option<Normalizer>,
+    digest: PhantomData<D>,
+    rolling_hash: PhantomData<R>,
 }
 
 /// Strong hash of a block for signature
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BlockHash {
-    pub block_index: u16,
-    pub hash: Vec<u8>,
+    pub block_index: u64,
+    /// Output of the signature digest, truncated to the signature's strong hash length
+    pub hash: StrongHash,
+}
+
+/// Most bytes of a strong hash kept per block, the output size of Blake2b
+pub const MAX_STRONG_HASH_LEN: usize = 64;
+
+/// The kept bytes of a strong hash, stored inline instead of on the heap
+///
+/// A block of a signature is 80 bytes without any allocation, where a `Vec` of the hash
+/// took 24 bytes with a separate allocation of the hash. With millions of blocks that is
+/// millions of allocations fewer. Longer digest outputs are truncated to
+/// `MAX_STRONG_HASH_LEN` bytes. Derefs to the kept bytes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StrongHash {
+    bytes: [u8; MAX_STRONG_HASH_LEN],
+    len: u8,
+}
+
+impl StrongHash {
+    /// The first `MAX_STRONG_HASH_LEN` bytes of `hash` at most
+    pub fn new(hash: &[u8]) -> Self {
+        let len = hash.len().min(MAX_STRONG_HASH_LEN);
+        let mut bytes = [0; MAX_STRONG_HASH_LEN];
+        bytes[..len].copy_from_slice(&hash[..len]);
+        Self {
+            bytes,
+            len: len as u8,
+        }
+    }
+}
+
+impl Deref for StrongHash {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
 }
 
+impl fmt::Debug for StrongHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self[..].fmt(f)
+    }
+}
+
+/// How the blocks of a signature spread over their weak checksums, see `collision_stats`
+#[derive(Debug, Default, PartialEq)]
+pub struct CollisionStats {
+    /// Weak checksums of at least one block
+    pub distinct_checksums: usize,
+    /// Most blocks sharing one weak checksum
+    pub max_bucket_len: usize,
+    /// Weak checksums shared by more than one block
+    pub collided_buckets: usize,
+}
+
+/// A block of one signature that has no equal in the other, see `Signature::diff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockChange {
+    /// Index of a block of `self` missing from the other signature
+    Removed(u64),
+    /// Index of a block of the other signature missing from `self`
+    Added(u64),
+}
+
+/// Reasons an encoded signature cannot be read back
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The input ended before the header or a block was complete
+    UnexpectedEnd,
+    /// There are bytes left after the last block
+    TrailingBytes,
+    /// The stored strong hashes are empty or longer than the digest output
+    InvalidHashLength,
+    /// The file size does not add up to the blocks of the signature
+    InvalidFileSize,
+    /// The block size is larger than both the file and `MAX_DECODED_BLOCK_SIZE`, or the
+    /// average size of blocks cut by content is no power of two of 64 up to that size
+    InvalidBlockSize,
+    /// The window of a `Differ` snapshot is not a block of the signature it is restored with
+    InvalidWindow,
+}
+
+/// block_size, blocks and file_size as u64, the strong hash length as u8, the seed as u64
+/// and the algorithm id as u32
+const HEADER_LEN: usize = 8 * 3 + 1 + 8 + 4;
+/// Hashed by both algorithms for their id
+const ALGORITHM_PROBE: &[u8] = b"Solemnly swear the algorithms are the same";
+/// Encoded block_size of signatures cut by content, the average size follows the header
+const CONTENT_DEFINED: u64 = 0;
+/// Largest block size read back for a file shorter than a block, a diff allocates a block
+pub const MAX_DECODED_BLOCK_SIZE: u64 = 1 << 24;
+
 impl Signature {
     pub fn new(_block_size: usize) -> Self {
+        Self::with_digest(_block_size)
+    }
+
+    /// Same as `new` with room for `expected_blocks` blocks, so generating does not rehash
+    pub fn with_capacity(block_size: usize, expected_blocks: usize) -> Self {
+        let mut sig = Self::new(block_size);
+        sig.reserve_blocks(expected_blocks);
+        sig
+    }
+
+    /// Signature of blocks of `block_size` bytes of everything `old` reads
+    ///
+    /// Generated once, one signature can be diffed against any number of new files with
+    /// `diff_against_signature`, the diffs keep their state to themselves.
+    pub fn from_reader(mut old: impl Read, block_size: usize) -> Result<Self, DiffError> {
+        let mut sig = Self::with_digest(block_size);
+        sig.generate(&mut old)?;
+        Ok(sig)
+    }
+
+    /// Same as `from_reader` for a file already in memory, sized for its blocks up front
+    pub fn from_slice(mut data: &[u8], block_size: usize) -> Result<Self, DiffError> {
+        let mut sig = Self::with_capacity(block_size, expected_blocks(data.len(), block_size));
+        sig.generate(&mut data)?;
+        Ok(sig)
+    }
+}
+
+impl<D: Digest, R: RollingHash> Signature<D, R> {
+    /// Same as `new` but with strong hashes computed by the digest `D` and weak checksums by `R`
+    pub fn with_digest(_block_size: usize) -> Self {
         Self {
-            chunk_hashes: HashMap::new(),
+            chunk_hashes: BlockMap::new(),
             block_size: _block_size,
             blocks: 0,
             file_size: 0,
+            strong_hash_len: D::output_size().min(MAX_STRONG_HASH_LEN),
+            chunking: None,
+            block_offsets: Vec::new(),
+            tail: Vec::new(),
+            seed: 0,
+            byte_map: None,
+            algorithm_id: algorithm_id::<D, R>(),
+            normalize: None,
+            digest: PhantomData,
+            rolling_hash: PhantomData,
         }
     }
 
+    /// Keep only the first `len` bytes of every strong hash, clamped to the digest output size
+    /// and `MAX_STRONG_HASH_LEN`
+    ///
+    /// This shrinks the signature at the cost of collision resistance: a block is trusted
+    /// once its weak checksum and the kept prefix match, so with 8 bytes a false match still
+    /// needs a weak collision and a 1 in 2^64 strong one, with 1 or 2 bytes random data
+    /// will regularly be patched with the wrong block.
+    pub fn with_strong_hash_len(mut self, len: usize) -> Self {
+        self.strong_hash_len = len.clamp(1, D::output_size().min(MAX_STRONG_HASH_LEN));
+        self
+    }
+
+    /// Mixes `seed` into the weak checksums so their collisions cannot be computed in advance
+    ///
+    /// Every byte is swapped for another by a permutation derived from the seed before it
+    /// is summed, so an attacker not knowing the seed cannot craft blocks that all land in
+    /// the same bucket. The seed is encoded with the signature and used by the diff
+    /// against it, a seed of 0 leaves the checksums unseeded. Set it before generating.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.byte_map = match seed {
+            0 => None,
+            _ => Some(byte_map(seed)),
+        };
+        self
+    }
+
+    /// Makes room for `expected_blocks` more blocks
+    pub(crate) fn reserve_blocks(&mut self, expected_blocks: usize) {
+        // a `BTreeMap` allocates its nodes as it grows
+        #[cfg(feature = "std")]
+        self.chunk_hashes.reserve(expected_blocks);
+        #[cfg(not(feature = "std"))]
+        let _ = expected_blocks;
+    }
+
+    /// Hashes every block as `normalize` maps it, e.g. to ignore case or trailing spaces
+    ///
+    /// The windows of the new file are normalized the same way when diffed against the
+    /// signature, so blocks equal once normalized are copied while added bytes stay as
+    /// they are. The windows are then hashed whole instead of rolled, and a normalizer is
+    /// not encoded with the signature. Set it before generating.
+    pub fn with_normalizer(mut self, normalize: Normalizer) -> Self {
+        self.normalize = Some(normalize);
+        self
+    }
+
     /// Main function that generates a signature
-    pub fn generate(&mut self, input: &mut dyn Read) {
-        // TODO define input type
+    ///
+    /// Any blocks from before are dropped first, see `clear`, so generating again or over
+    /// a decoded signature gives the signature of `input` alone. Fails with
+    /// `DiffError::InvalidBlockSize` if the blocks are 0 bytes.
+    pub fn generate(&mut self, input: &mut dyn Read) -> Result<(), DiffError> {
+        self.clear();
+        self.append(input)
+    }
+
+    /// Forgets every block so the signature can be generated again for another file
+    ///
+    /// The memory of the blocks is kept for the next file, as are the block size, strong
+    /// hash length, seed and normalizer. Blocks cut by content are cut again by
+    /// `generate_chunked`, `generate` fails for them as they have no block size. The
+    /// algorithm id of a decoded signature becomes the one of `D` and `R` again.
+    pub fn clear(&mut self) {
+        self.chunk_hashes.clear();
+        self.blocks = 0;
+        self.file_size = 0;
+        self.chunking = None;
+        self.block_offsets.clear();
+        self.tail.clear();
+        self.algorithm_id = algorithm_id::<D, R>();
+    }
+
+    /// Adds the blocks of bytes appended to the file, the existing blocks are kept
+    ///
+    /// A short last block is completed with the appended bytes and hashed again. Fails if
+    /// that block was not generated by this signature, e.g. one decoded or cut by content.
+    pub fn append(&mut self, input: &mut dyn Read) -> Result<(), DiffError> {
+        if self.block_size == 0 && self.chunking.is_none() {
+            return Err(DiffError::InvalidBlockSize);
+        }
+        if self.chunking.is_some()
+            || self.tail.len() as u64 != self.file_size % self.block_size as u64
+        {
+            return Err(DiffError::NotAppendable);
+        }
         let mut buf = vec![0; self.block_size];
-        let mut read_size = input.read(&mut buf).unwrap(); // handle
-        let mut rs = Rollsum::new(&buf);
-        if read_size == 0 {
-            self.blocks = 0;
-            self.file_size = 0;
-        }
-        while read_size > 0 {
-            let mut blake_hasher = Blake2b::new();
-            blake_hasher.update(&buf);
-            let hash = blake_hasher.finalize();
-            let hashes = self.chunk_hashes.entry(rs.digest()).or_insert(Vec::new());
-            hashes.push(BlockHash {
-                block_index: self.blocks,
-                hash: hash.as_slice().to_owned(),
-            });
-            self.blocks += 1;
-            self.file_size += read_size as u16;
-            read_size = input.read(&mut buf).unwrap();
-            rs.batch_roll(&buf).unwrap();
+        let mut filled = self.tail.len();
+        if filled > 0 {
+            buf[..filled].copy_from_slice(&self.tail);
+            self.pop_block(self.weak_checksum(&self.tail), filled);
+            self.tail.clear();
+        }
+        loop {
+            filled += fill_block(input, &mut buf[filled..])?;
+            if filled == 0 {
+                break;
+            }
+            let block = &buf[..filled];
+            self.push_block(block, filled);
+            // only the final block can be short, nothing is left to read after it
+            if filled < self.block_size {
+                self.tail = block.to_vec();
+                break;
+            }
+            filled = 0;
+        }
+        Ok(())
+    }
+
+    /// Generates a signature of blocks cut by content, see `ContentDefinedChunking`
+    ///
+    /// The whole input is read first since a boundary depends on the bytes before it.
+    pub fn generate_chunked(
+        &mut self,
+        input: &mut dyn Read,
+        chunking: ContentDefinedChunking,
+    ) -> Result<(), DiffError> {
+        let buf = read_all(input)?;
+        self.chunking = Some(chunking);
+        self.generate_cut(&buf, &chunking.block_lens(&buf));
+        Ok(())
+    }
+
+    /// Generates a signature of `buf` cut into blocks of `lens` bytes, in order
+    pub(crate) fn generate_cut(&mut self, buf: &[u8], lens: &[usize]) {
+        let mut offset = 0;
+        for &len in lens {
+            let block = &buf[offset..offset + len];
+            self.block_offsets.push(offset as u64);
+            self.push_block(block, len);
+            offset += len;
+        }
+    }
+
+    /// Same as `generate` but the blocks are hashed on all available threads
+    ///
+    /// The whole input is read first and split into one run of blocks per thread, the
+    /// hashes are recorded in block order once every thread is done.
+    #[cfg(feature = "std")]
+    pub fn generate_parallel(&mut self, input: &mut dyn Read) -> Result<(), DiffError> {
+        if self.block_size == 0 {
+            return Err(DiffError::InvalidBlockSize);
+        }
+        self.clear();
+        let mut buf = Vec::new();
+        input.read_to_end(&mut buf)?;
+        let blocks: Vec<&[u8]> = buf.chunks(self.block_size).collect();
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let blocks_per_thread = blocks.len().div_ceil(threads).max(1);
+        let strong_hash_len = self.strong_hash_len;
+        let byte_map = self.byte_map;
+        let normalize = self.normalize;
+        let hashed: Vec<Vec<(u32, StrongHash)>> = thread::scope(|scope| {
+            let runs: Vec<_> = blocks
+                .chunks(blocks_per_thread)
+                .map(|run| {
+                    scope.spawn(move || {
+                        run.iter()
+                            .map(|block| {
+                                let block = normalized(normalize, block);
+                                let checksum = R::new(&seeded(&byte_map, &block)).digest();
+                                (checksum, block_hash::<D>(&block, strong_hash_len))
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            runs.into_iter().map(|run| run.join().unwrap()).collect()
+        });
+        for ((checksum, hash), block) in hashed.into_iter().flatten().zip(&blocks) {
+            self.insert_block(checksum, hash, block.len());
+        }
+        if let Some(last) = blocks.last().filter(|last| last.len() < self.block_size) {
+            self.tail = last.to_vec();
+        }
+        Ok(())
+    }
+
+    /// Records the hashes of `block` as the next block of `len` bytes
+    fn push_block(&mut self, block: &[u8], len: usize) {
+        let checksum = self.weak_checksum(block);
+        let hash = self.strong_hash(block);
+        self.insert_block(checksum, hash, len);
+    }
+
+    /// Drops the last block of `len` bytes, which has the weak `checksum`
+    fn pop_block(&mut self, checksum: u32, len: usize) {
+        self.blocks -= 1;
+        self.file_size -= len as u64;
+        let last = self.blocks;
+        let hashes = self.chunk_hashes.get_mut(&checksum).unwrap();
+        hashes.retain(|block| block.block_index != last);
+        if hashes.is_empty() {
+            self.chunk_hashes.remove(&checksum);
+        }
+    }
+
+    fn insert_block(&mut self, checksum: u32, hash: StrongHash, len: usize) {
+        let hashes = self.chunk_hashes.entry(checksum).or_default();
+        hashes.push(BlockHash {
+            block_index: self.blocks,
+            hash,
+        });
+        self.blocks += 1;
+        self.file_size += len as u64;
+    }
+
+    /// Index of the first block of the signature equal to `block`, if any
+    ///
+    /// The weak checksum is looked up first, the strong hash only computed if it is found.
+    pub fn contains_block(&self, block: &[u8]) -> Option<u64> {
+        let candidates = self.get_chunk_map(self.weak_checksum(block))?;
+        let hash = self.strong_hash(block);
+        candidates
+            .iter()
+            .find(|candidate| hashes_equal(&candidate.hash, &hash))
+            .map(|candidate| candidate.block_index)
+    }
+
+    /// Blocks of either signature without a block of the same hashes in the other
+    ///
+    /// Only the signatures are compared, e.g. to tell which blocks changed without the
+    /// files. Blocks are equal if their weak checksums and the strong hash bytes kept by both
+    /// agree, so signatures of different seeds or algorithms have no blocks in common.
+    /// Removed blocks come first, each kind in block order.
+    pub fn diff<E: Digest, S: RollingHash>(&self, other: &Signature<E, S>) -> Vec<BlockChange> {
+        let hash_len = self.strong_hash_len.min(other.strong_hash_len);
+        let comparable = self.seed == other.seed && self.algorithm_id == other.algorithm_id;
+        let missing = |from: &BlockMap, to: &BlockMap| {
+            let mut missing: Vec<u64> = from
+                .iter()
+                .flat_map(|(checksum, blocks)| {
+                    blocks.iter().filter(move |block| {
+                        !comparable
+                            || !to.get(checksum).is_some_and(|others| {
+                                others.iter().any(|other| {
+                                    hashes_equal(&other.hash[..hash_len], &block.hash[..hash_len])
+                                })
+                            })
+                    })
+                })
+                .map(|block| block.block_index)
+                .collect();
+            missing.sort_unstable();
+            missing
+        };
+        let removed = missing(&self.chunk_hashes, &other.chunk_hashes);
+        let added = missing(&other.chunk_hashes, &self.chunk_hashes);
+        removed
+            .into_iter()
+            .map(BlockChange::Removed)
+            .chain(added.into_iter().map(BlockChange::Added))
+            .collect()
+    }
+
+    /// Spread of the blocks over weak checksums, each collision costs a strong hash when diffing
+    pub fn collision_stats(&self) -> CollisionStats {
+        let mut stats = CollisionStats {
+            distinct_checksums: self.chunk_hashes.len(),
+            ..CollisionStats::default()
+        };
+        for bucket in self.chunk_hashes.values() {
+            stats.max_bucket_len = stats.max_bucket_len.max(bucket.len());
+            if bucket.len() > 1 {
+                stats.collided_buckets += 1;
+            }
         }
+        stats
+    }
+
+    /// Index, weak checksum and strong hash of every block, in block order
+    ///
+    /// The blocks are kept by weak checksum, so they are collected and sorted first.
+    pub fn blocks_in_order(&self) -> impl Iterator<Item = (u64, u32, &[u8])> {
+        let mut blocks: Vec<(u64, u32, &[u8])> = self
+            .chunk_hashes
+            .iter()
+            .flat_map(|(checksum, blocks)| {
+                blocks
+                    .iter()
+                    .map(move |block| (block.block_index, *checksum, &block.hash[..]))
+            })
+            .collect();
+        blocks.sort_unstable_by_key(|(block_index, _, _)| *block_index);
+        blocks.into_iter()
+    }
+
+    /// Whether `bytes` hash the same as the block at `block_index`
+    #[cfg(feature = "std")]
+    pub(crate) fn block_matches(&self, block_index: u64, bytes: &[u8]) -> bool {
+        let hash = self.strong_hash(bytes);
+        self.get_chunk_map(self.weak_checksum(bytes))
+            .is_some_and(|blocks| {
+                blocks.iter().any(|block| {
+                    block.block_index == block_index && hashes_equal(&block.hash, &hash)
+                })
+            })
     }
 
     pub fn get_chunk_map(&self, key: u32) -> Option<&Vec<BlockHash>> {
         self.chunk_hashes.get(&key)
     }
 
-    pub fn get_file_size(&self) -> u16 {
+    /// Bytes per block the file was cut into, 0 if cut by content
+    pub fn get_block_size(&self) -> usize {
+        if self.chunking.is_some() {
+            return 0;
+        }
+        self.block_size
+    }
+
+    pub fn get_file_size(&self) -> u64 {
         self.file_size
     }
 
-    pub fn get_blocks(&self) -> u16 {
+    pub fn get_blocks(&self) -> u64 {
         self.blocks
     }
+
+    pub fn get_strong_hash_len(&self) -> usize {
+        self.strong_hash_len
+    }
+
+    pub fn get_chunking(&self) -> Option<ContentDefinedChunking> {
+        self.chunking
+    }
+
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Id of the digest and rolling hash the signature was generated with
+    ///
+    /// A decoded signature keeps the id it was encoded with, whatever `D` and `R` are.
+    pub fn get_algorithm_id(&self) -> u32 {
+        self.algorithm_id
+    }
+
+    /// `bytes` as they are hashed, see `with_normalizer`
+    pub(crate) fn normalized<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        normalized(self.normalize, bytes)
+    }
+
+    /// Weak checksum of `bytes` as a block of this signature
+    pub(crate) fn weak_checksum(&self, bytes: &[u8]) -> u32 {
+        R::new(&self.seeded(&self.normalized(bytes))).digest()
+    }
+
+    /// Strong hash of `bytes` as a block of this signature, truncated to the stored length
+    pub(crate) fn strong_hash(&self, bytes: &[u8]) -> StrongHash {
+        block_hash::<D>(&self.normalized(bytes), self.strong_hash_len)
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn is_normalized(&self) -> bool {
+        self.normalize.is_some()
+    }
+
+    /// `bytes` as they are fed to the weak checksum
+    pub(crate) fn seeded<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        seeded(&self.byte_map, bytes)
+    }
+
+    /// `byte` as it is fed to the weak checksum
+    #[cfg(feature = "std")]
+    pub(crate) fn seeded_byte(&self, byte: u8) -> u8 {
+        self.byte_map
+            .map_or(byte, |byte_map| byte_map[byte as usize])
+    }
+
+    /// Bytes of the original file covered by the block
+    pub fn get_block_range(&self, block_index: u64) -> Range<u64> {
+        if !self.block_offsets.is_empty() {
+            let start = self.block_offsets[block_index as usize];
+            let end = self
+                .block_offsets
+                .get(block_index as usize + 1)
+                .cloned()
+                .unwrap_or(self.file_size);
+            start..end
+        } else {
+            let start = block_index * self.block_size as u64;
+            start..self.file_size.min(start + self.block_size as u64)
+        }
+    }
+
+    /// Binary encoding of the signature with the stored strong hash of every block
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with_hash_len(self.strong_hash_len)
+    }
+
+    /// Binary encoding of the signature keeping only the first `hash_len` bytes of the
+    /// strong hashes
+    ///
+    /// The header holds block_size, blocks and file_size as big endian u64, the kept hash
+    /// length as u8, the seed as u64 and the algorithm id as u32, then every block in order
+    /// as its 4 byte weak checksum and its strong hash.
+    /// Signatures cut by content store a block_size of 0 with the average block size as u64
+    /// after the header, and the u32 length of every block after its weak checksum.
+    pub fn encode_with_hash_len(&self, hash_len: usize) -> Vec<u8> {
+        let hash_len = hash_len.clamp(1, self.strong_hash_len.min(u8::MAX as usize));
+        let blocks = self.blocks as usize;
+        let mut out = Vec::with_capacity(HEADER_LEN + 8 + blocks * (8 + hash_len));
+        match self.chunking {
+            Some(_) => out.extend_from_slice(&CONTENT_DEFINED.to_be_bytes()),
+            None => out.extend_from_slice(&(self.block_size as u64).to_be_bytes()),
+        }
+        out.extend_from_slice(&self.blocks.to_be_bytes());
+        out.extend_from_slice(&self.file_size.to_be_bytes());
+        out.push(hash_len as u8);
+        out.extend_from_slice(&self.seed.to_be_bytes());
+        out.extend_from_slice(&self.algorithm_id.to_be_bytes());
+        if let Some(chunking) = self.chunking {
+            out.extend_from_slice(&(chunking.avg_size() as u64).to_be_bytes());
+        }
+        // in block order, whatever order the map holds them in
+        for (block_index, checksum, hash) in self.blocks_in_order() {
+            out.extend_from_slice(&checksum.to_be_bytes());
+            if self.chunking.is_some() {
+                let range = self.get_block_range(block_index);
+                out.extend_from_slice(&((range.end - range.start) as u32).to_be_bytes());
+            }
+            out.extend_from_slice(&hash[..hash_len]);
+        }
+        out
+    }
+
+    /// Reads a signature produced by `encode` or `encode_with_hash_len`
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let read_u64 = |at: usize| {
+            let mut be = [0u8; 8];
+            be.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_be_bytes(be)
+        };
+        let block_size = read_u64(0);
+        let file_size = read_u64(16);
+        // a block longer than the file is fine for small files, not to allocate any size
+        if block_size > file_size.max(MAX_DECODED_BLOCK_SIZE) || block_size > usize::MAX as u64 {
+            return Err(DecodeError::InvalidBlockSize);
+        }
+        let mut sig = Self::with_digest(block_size as usize).with_seed(read_u64(25));
+        sig.blocks = read_u64(8);
+        sig.file_size = file_size;
+        let hash_len = bytes[24] as usize;
+        if hash_len == 0 || hash_len > D::output_size().min(MAX_STRONG_HASH_LEN) {
+            return Err(DecodeError::InvalidHashLength);
+        }
+        sig.strong_hash_len = hash_len;
+        let mut algorithm_id = [0u8; 4];
+        algorithm_id.copy_from_slice(&bytes[33..HEADER_LEN]);
+        sig.algorithm_id = u32::from_be_bytes(algorithm_id);
+
+        let mut blocks_start = HEADER_LEN;
+        let mut len_size = 0;
+        if read_u64(0) == CONTENT_DEFINED {
+            if bytes.len() < HEADER_LEN + 8 {
+                return Err(DecodeError::UnexpectedEnd);
+            }
+            let avg_size = read_u64(HEADER_LEN);
+            // encoded as `ContentDefinedChunking::new` rounded it, a larger one would overflow
+            if !avg_size.is_power_of_two() || !(64..=MAX_DECODED_BLOCK_SIZE).contains(&avg_size) {
+                return Err(DecodeError::InvalidBlockSize);
+            }
+            sig.chunking = Some(ContentDefinedChunking::new(avg_size as usize));
+            blocks_start += 8;
+            len_size = 4;
+        }
+
+        let mut offset = 0u64;
+        let mut blocks = bytes[blocks_start..].chunks(4 + len_size + hash_len);
+        for block_index in 0..sig.blocks {
+            let block = match blocks.next() {
+                Some(block) if block.len() == 4 + len_size + hash_len => block,
+                _ => return Err(DecodeError::UnexpectedEnd),
+            };
+            let mut checksum = [0u8; 4];
+            checksum.copy_from_slice(&block[..4]);
+            if sig.chunking.is_some() {
+                let mut len = [0u8; 4];
+                len.copy_from_slice(&block[4..8]);
+                sig.block_offsets.push(offset);
+                offset += u32::from_be_bytes(len) as u64;
+            }
+            sig.chunk_hashes
+                .entry(u32::from_be_bytes(checksum))
+                .or_default()
+                .push(BlockHash {
+                    block_index,
+                    hash: StrongHash::new(&block[4 + len_size..]),
+                });
+        }
+        if blocks.next().is_some() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        // every block but the last is whole, the last one has at least a byte
+        let file_size_fits = match sig.chunking {
+            Some(_) => offset == sig.file_size,
+            None => sig.file_size.div_ceil(sig.block_size.max(1) as u64) == sig.blocks,
+        };
+        if !file_size_fits {
+            return Err(DecodeError::InvalidFileSize);
+        }
+        Ok(sig)
+    }
+}
+
+/// Id of the pair of algorithms, the FNV hash of what both output for a probe
+///
+/// Unlike a registry of ids this covers any digest and rolling hash, two different pairs
+/// share an id about 1 time in 4 billion.
+pub(crate) fn algorithm_id<D: Digest, R: RollingHash>() -> u32 {
+    let strong = D::digest(ALGORITHM_PROBE);
+    let weak = R::new(ALGORITHM_PROBE).digest().to_be_bytes();
+    // FNV-1a
+    let mut hash: u32 = 0x811C_9DC5;
+    for byte in strong.iter().chain(weak.iter()) {
+        hash = (hash ^ *byte as u32).wrapping_mul(0x0100_0193);
+    }
+    hash
 }
 
-#[cfg(test)]
+/// Everything `input` reads
+fn read_all(input: &mut dyn Read) -> Result<Vec<u8>, DiffError> {
+    let mut buf = Vec::new();
+    #[cfg(feature = "std")]
+    input.read_to_end(&mut buf)?;
+    #[cfg(not(feature = "std"))]
+    loop {
+        let filled = buf.len();
+        buf.resize(filled + 4096, 0);
+        let read = input.read(&mut buf[filled..])?;
+        buf.truncate(filled + read);
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(buf)
+}
+
+/// Blocks of `block_size` bytes a file of `file_len` bytes is cut into, 0 without a block size
+pub(crate) fn expected_blocks(file_len: usize, block_size: usize) -> usize {
+    match block_size {
+        0 => 0,
+        _ => file_len.div_ceil(block_size),
+    }
+}
+
+/// `bytes` as hashed for a block, mapped by `normalize` if any
+fn normalized(normalize: Option<Normalizer>, bytes: &[u8]) -> Cow<'_, [u8]> {
+    match normalize {
+        Some(normalize) => Cow::Owned(normalize(bytes)),
+        None => Cow::Borrowed(bytes),
+    }
+}
+
+/// Replaces every byte by its entry in `byte_map`, if any
+fn seeded<'a>(byte_map: &Option<[u8; 256]>, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+    match byte_map {
+        Some(byte_map) => Cow::Owned(bytes.iter().map(|byte| byte_map[*byte as usize]).collect()),
+        None => Cow::Borrowed(bytes),
+    }
+}
+
+/// The first `len` bytes of the `D` hash of `block`, without allocating
+fn block_hash<D: Digest>(block: &[u8], len: usize) -> StrongHash {
+    let mut hasher = D::new();
+    hasher.update(block);
+    StrongHash::new(&hasher.finalize()[..len])
+}
+
+/// Permutation of all bytes shuffled by splitmix64 from `seed`
+fn byte_map(seed: u64) -> [u8; 256] {
+    let mut byte_map = [0u8; 256];
+    for (i, byte) in byte_map.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let mut state = seed;
+    for i in (1..256).rev() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        byte_map.swap(i, (z % (i as u64 + 1)) as usize);
+    }
+    byte_map
+}
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use std::io::Cursor;
@@ -81,8 +809,477 @@ mod tests {
             "Words are, in my not-so-humble opinion, our most inexhaustible source of magic.",
         ); // 79 characters
         let mut sig = Signature::new(8);
-        sig.generate(&mut input);
+        sig.generate(&mut input).unwrap();
         assert_eq!(sig.get_blocks(), 10);
         assert_eq!(sig.get_file_size(), 79)
     }
+
+    /// Hands out at most one byte per read, like a slow pipe
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl Read for OneByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn short_reads_produce_same_signature() {
+        let input =
+            "Words are, in my not-so-humble opinion, our most inexhaustible source of magic.";
+        let mut sig = Signature::new(8);
+        sig.generate(&mut Cursor::new(input)).unwrap();
+        let mut slow_sig = Signature::new(8);
+        slow_sig
+            .generate(&mut OneByteReader(input.as_bytes()))
+            .unwrap();
+
+        assert_eq!(slow_sig.get_blocks(), sig.get_blocks());
+        assert_eq!(slow_sig.get_file_size(), sig.get_file_size());
+        assert_eq!(slow_sig.chunk_hashes, sig.chunk_hashes);
+    }
+
+    #[test]
+    fn encoded_signature_decodes_to_same_signature() {
+        let input =
+            "Words are, in my not-so-humble opinion, our most inexhaustible source of magic.";
+        let mut sig = Signature::new(8);
+        sig.generate(&mut Cursor::new(input)).unwrap();
+
+        let decoded: Signature = Signature::decode(&sig.encode()).unwrap();
+        assert_eq!(decoded.get_block_size(), 8);
+        assert_eq!(decoded.get_strong_hash_len(), Blake2b::output_size());
+        assert_eq!(decoded.get_blocks(), sig.get_blocks());
+        assert_eq!(decoded.get_file_size(), sig.get_file_size());
+        let checksum = Rollsum::new(b"Words ar").digest();
+        assert_eq!(decoded.get_chunk_map(checksum), sig.get_chunk_map(checksum));
+
+        // only the kept prefix of the strong hash is sent
+        let truncated: Signature = Signature::decode(&sig.encode_with_hash_len(8)).unwrap();
+        assert_eq!(truncated.get_strong_hash_len(), 8);
+        assert_eq!(truncated.get_blocks(), sig.get_blocks());
+        let block = &truncated.get_chunk_map(checksum).unwrap()[0];
+        assert_eq!(block.block_index, 0);
+        assert_eq!(
+            block.hash[..],
+            sig.get_chunk_map(checksum).unwrap()[0].hash[..8]
+        );
+    }
+
+    #[test]
+    fn decoding_truncated_signature_fails() {
+        let mut sig = Signature::new(8);
+        sig.generate(&mut Cursor::new("Words are, in my not-so-humble opinion"))
+            .unwrap();
+        let encoded = sig.encode();
+        assert_eq!(
+            Signature::<Blake2b>::decode(&encoded[..encoded.len() - 1]).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+        assert_eq!(
+            Signature::<Blake2b>::decode(&encoded[..10]).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+    }
+
+    #[test]
+    fn decoding_checks_the_file_size_against_the_blocks() {
+        let mut sig = Signature::new(8);
+        sig.generate(&mut Cursor::new("Words are, in my not-so-humble opinion"))
+            .unwrap();
+        assert_eq!(sig.get_file_size(), 38);
+        let mut encoded = sig.encode();
+        // 5 blocks of 8 bytes hold 33 to 40 bytes
+        for (file_size, fits) in [(32u64, false), (33, true), (40, true), (41, false)] {
+            encoded[16..24].copy_from_slice(&file_size.to_be_bytes());
+            let decoded = Signature::<Blake2b>::decode(&encoded);
+            match fits {
+                true => assert_eq!(decoded.unwrap().get_file_size(), file_size),
+                false => assert_eq!(decoded.unwrap_err(), DecodeError::InvalidFileSize),
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_rejects_blocks_larger_than_needed() {
+        let mut sig = Signature::new(8);
+        sig.generate(&mut Cursor::new(&b"L"[..])).unwrap();
+        let mut encoded = sig.encode();
+        // a block of a terabyte for a file of a byte
+        encoded[..8].copy_from_slice(&(1u64 << 40).to_be_bytes());
+        assert_eq!(
+            Signature::<Blake2b>::decode(&encoded).unwrap_err(),
+            DecodeError::InvalidBlockSize
+        );
+        // a file shorter than its one block is read back up to the limit
+        encoded[..8].copy_from_slice(&MAX_DECODED_BLOCK_SIZE.to_be_bytes());
+        let decoded = Signature::<Blake2b>::decode(&encoded).unwrap();
+        assert_eq!(decoded.get_block_size() as u64, MAX_DECODED_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn seeds_change_the_weak_checksums_only() {
+        let input = crate::tests::pseudo_random(64 * 100);
+        let generate = |seed| {
+            let mut sig = Signature::new(64).with_seed(seed);
+            sig.generate(&mut Cursor::new(&input)).unwrap();
+            sig
+        };
+        let (unseeded, seeded, reseeded) = (generate(0), generate(1), generate(2));
+        let checksums = |sig: &Signature| {
+            let mut checksums: Vec<u32> = sig.chunk_hashes.keys().cloned().collect();
+            checksums.sort_unstable();
+            checksums
+        };
+        assert_ne!(checksums(&seeded), checksums(&unseeded));
+        assert_ne!(checksums(&seeded), checksums(&reseeded));
+        assert_eq!(seeded.get_blocks(), unseeded.get_blocks());
+
+        let decoded = Signature::<Blake2b>::decode(&seeded.encode()).unwrap();
+        assert_eq!(decoded.get_seed(), 1);
+        assert_eq!(decoded.chunk_hashes, seeded.chunk_hashes);
+    }
+
+    #[test]
+    fn algorithm_ids_tell_the_built_in_algorithms_apart() {
+        use crate::{Blake2s, Crc32, RabinKarp};
+        let ids = [
+            algorithm_id::<Blake2b, Rollsum>(),
+            algorithm_id::<Blake2b, Crc32>(),
+            algorithm_id::<Blake2b, RabinKarp>(),
+            algorithm_id::<Blake2s, Rollsum>(),
+            algorithm_id::<Blake2s, Crc32>(),
+            algorithm_id::<Blake2s, RabinKarp>(),
+        ];
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[..i].contains(id));
+        }
+
+        let mut sig = Signature::<Blake2s, Crc32>::with_digest(8);
+        sig.generate(&mut Cursor::new("Words are, in my not-so-humble opinion"))
+            .unwrap();
+        assert_eq!(sig.get_algorithm_id(), ids[4]);
+        let decoded = Signature::<Blake2b>::decode(&sig.encode()).unwrap();
+        assert_eq!(decoded.get_algorithm_id(), ids[4]);
+
+        // encodings from before the id took 4 bytes are cut short
+        let mut encoded = sig.encode();
+        encoded.drain(34..HEADER_LEN);
+        assert_eq!(
+            Signature::<Blake2s, Crc32>::decode(&encoded).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+    }
+
+    #[test]
+    fn blocks_are_looked_up_by_content() {
+        let input = "Words are, in my not-so-humble opinion";
+        let mut sig = Signature::new(8).with_seed(3);
+        sig.generate(&mut Cursor::new(input)).unwrap();
+        assert_eq!(sig.contains_block(b"Words ar"), Some(0));
+        assert_eq!(sig.contains_block(b" not-so-"), Some(2));
+        assert_eq!(sig.contains_block(b"pinion"), Some(4));
+        assert_eq!(sig.contains_block(b"Words a"), None);
+        assert_eq!(sig.contains_block(b"Sword ar"), None);
+    }
+
+    #[test]
+    fn signatures_are_diffed_by_block() {
+        let input = crate::tests::pseudo_random(64 * 10);
+        let mut changed = input.clone();
+        changed[64 * 3 + 5] ^= 1;
+        let mut old = Signature::new(64);
+        old.generate(&mut Cursor::new(&input)).unwrap();
+        let mut new = Signature::new(64);
+        new.generate(&mut Cursor::new(&changed)).unwrap();
+
+        assert_eq!(
+            old.diff(&new),
+            vec![BlockChange::Removed(3), BlockChange::Added(3)]
+        );
+        assert!(old.diff(&old).is_empty());
+        // only the kept bytes of the strong hashes are compared
+        let truncated: Signature = Signature::decode(&new.encode_with_hash_len(4)).unwrap();
+        assert_eq!(old.diff(&truncated), old.diff(&new));
+
+        let mut seeded = Signature::new(64).with_seed(9);
+        seeded.generate(&mut Cursor::new(&input)).unwrap();
+        assert_eq!(old.diff(&seeded).len(), 20);
+    }
+
+    #[test]
+    fn presized_signatures_equal_default_ones() {
+        let input = crate::tests::pseudo_random(64 * 100 + 5);
+        let mut presized = Signature::with_capacity(64, 101);
+        let capacity = presized.chunk_hashes.capacity();
+        assert!(capacity >= 101);
+        presized.generate(&mut Cursor::new(&input)).unwrap();
+        // no rehash while generating
+        assert_eq!(presized.chunk_hashes.capacity(), capacity);
+
+        let mut default = Signature::new(64);
+        default.generate(&mut Cursor::new(&input)).unwrap();
+        assert_eq!(presized.encode(), default.encode());
+        let from_slice = Signature::from_slice(&input, 64).unwrap();
+        assert_eq!(from_slice.encode(), default.encode());
+        assert_eq!(expected_blocks(input.len(), 64), 101);
+        assert_eq!(expected_blocks(input.len(), 0), 0);
+    }
+
+    #[test]
+    fn exact_multiples_of_the_block_size_end_on_a_full_block() {
+        let input = crate::tests::pseudo_random(64 * 10);
+        let mut sig = Signature::new(64);
+        sig.generate(&mut Cursor::new(&input)).unwrap();
+        assert_eq!(sig.get_blocks(), 10);
+        assert_eq!(sig.get_file_size(), input.len() as u64);
+        assert!(sig.tail.is_empty());
+        let indices: Vec<u64> = sig.blocks_in_order().map(|(index, _, _)| index).collect();
+        assert_eq!(indices, (0..10).collect::<Vec<_>>());
+        assert_eq!(sig.get_block_range(9), 576..640);
+        let mut parallel = Signature::new(64);
+        parallel
+            .generate_parallel(&mut Cursor::new(&input))
+            .unwrap();
+        assert_eq!(
+            parallel.encode(),
+            Signature::from_slice(&input, 64).unwrap().encode()
+        );
+    }
+
+    #[test]
+    fn strong_hashes_are_stored_inline() {
+        // the index and the hash bytes with their length, no pointer to a heap allocation
+        assert_eq!(std::mem::size_of::<BlockHash>(), 80);
+        let sig = Signature::from_slice(b"Mischief managed", 8).unwrap();
+        let checksum = sig.weak_checksum(b"Mischief");
+        let hash = &sig.get_chunk_map(checksum).unwrap()[0].hash;
+        assert_eq!(hash.len(), MAX_STRONG_HASH_LEN);
+        assert_eq!(hash[..], crate::strong_hash(b"Mischief")[..]);
+
+        let truncated = StrongHash::new(&[7; 100]);
+        assert_eq!(truncated[..], [7; MAX_STRONG_HASH_LEN][..]);
+        assert_eq!(format!("{:?}", StrongHash::new(&[1, 2])), "[1, 2]");
+    }
+
+    #[test]
+    fn cleared_signatures_generate_like_fresh_ones() {
+        let first = crate::tests::pseudo_random(64 * 100);
+        let second = "Lumos".repeat(300);
+        let mut sig = Signature::new(64).with_seed(7).with_strong_hash_len(8);
+        sig.generate(&mut Cursor::new(&first)).unwrap();
+        let capacity = sig.chunk_hashes.capacity();
+        sig.clear();
+        assert_eq!(sig.get_blocks(), 0);
+        assert_eq!(sig.chunk_hashes.capacity(), capacity);
+        sig.generate(&mut Cursor::new(&second)).unwrap();
+
+        let mut fresh = Signature::new(64).with_seed(7).with_strong_hash_len(8);
+        fresh.generate(&mut Cursor::new(&second)).unwrap();
+        assert_eq!(sig.encode(), fresh.encode());
+        assert_eq!(sig.get_file_size(), second.len() as u64);
+    }
+
+    #[test]
+    fn regenerated_signatures_match_fresh_ones() {
+        let first = crate::tests::pseudo_random(1_000);
+        let second = "Lumos".repeat(300);
+        let mut fresh = Signature::new(64);
+        fresh.generate(&mut Cursor::new(&second)).unwrap();
+
+        let mut sig = Signature::new(64);
+        sig.generate(&mut Cursor::new(&first)).unwrap();
+        sig.generate(&mut Cursor::new(&second)).unwrap();
+        assert_eq!(sig.encode(), fresh.encode());
+        sig.generate_parallel(&mut Cursor::new(&first)).unwrap();
+        sig.generate_parallel(&mut Cursor::new(&second)).unwrap();
+        assert_eq!(sig.encode(), fresh.encode());
+
+        // the short last block of a decoded signature is dropped with the others
+        let mut decoded: Signature = Signature::decode(&sig.encode()).unwrap();
+        decoded.generate(&mut Cursor::new(&second)).unwrap();
+        assert_eq!(decoded.encode(), fresh.encode());
+    }
+
+    #[test]
+    fn collisions_are_counted_per_checksum() {
+        let mut repetitive = Signature::new(16);
+        repetitive
+            .generate(&mut Cursor::new("Lumos".repeat(1_000)))
+            .unwrap();
+        let stats = repetitive.collision_stats();
+        // the blocks repeat every 5 blocks, the short last one is on its own
+        assert_eq!(stats.distinct_checksums, 6);
+        assert!(stats.max_bucket_len > 1);
+        assert_eq!(stats.collided_buckets, 5);
+
+        let mut distinct = Signature::new(16);
+        distinct
+            .generate(&mut Cursor::new(crate::tests::pseudo_random(16_000)))
+            .unwrap();
+        let stats = distinct.collision_stats();
+        assert!(stats.distinct_checksums > 990);
+        assert!(stats.collided_buckets < 10);
+    }
+
+    #[test]
+    fn encodings_are_reproducible() {
+        let input = crate::tests::pseudo_random(64 * 500);
+        // every map is seeded at random, so two signatures keep their blocks in other orders
+        let encodings: Vec<Vec<u8>> = (0..4)
+            .map(|_| Signature::from_slice(&input, 64).unwrap().encode())
+            .collect();
+        assert!(encodings.iter().all(|encoding| *encoding == encodings[0]));
+        let decoded: Signature = Signature::decode(&encodings[0]).unwrap();
+        assert_eq!(decoded.encode(), encodings[0]);
+
+        let mut chunked = Signature::new(0);
+        chunked
+            .generate_chunked(&mut Cursor::new(&input), ContentDefinedChunking::new(256))
+            .unwrap();
+        let mut again = Signature::new(0);
+        again
+            .generate_chunked(&mut Cursor::new(&input), ContentDefinedChunking::new(256))
+            .unwrap();
+        assert_eq!(chunked.encode(), again.encode());
+    }
+
+    #[test]
+    fn blocks_are_listed_in_order() {
+        let data = "Lumos".repeat(100);
+        let sig = Signature::from_slice(data.as_bytes(), 16).unwrap();
+        let blocks: Vec<_> = sig.blocks_in_order().collect();
+        let indices: Vec<u64> = blocks
+            .iter()
+            .map(|(block_index, _, _)| *block_index)
+            .collect();
+        assert_eq!(indices, (0..sig.get_blocks()).collect::<Vec<_>>());
+        for (block_index, checksum, hash) in blocks {
+            let range = sig.get_block_range(block_index);
+            let block = &data.as_bytes()[range.start as usize..range.end as usize];
+            assert_eq!(checksum, sig.weak_checksum(block));
+            assert_eq!(hash, &sig.strong_hash(block)[..]);
+        }
+    }
+
+    #[test]
+    fn strong_hashes_are_truncated() {
+        let mut sig = Signature::new(8).with_strong_hash_len(8);
+        sig.generate(&mut Cursor::new("Words are, in my not-so-humble opinion"))
+            .unwrap();
+        assert_eq!(sig.get_strong_hash_len(), 8);
+        let checksum = Rollsum::new(b"Words ar").digest();
+        let mut full = Blake2b::new();
+        full.update(b"Words ar");
+        assert_eq!(
+            sig.get_chunk_map(checksum).unwrap()[0].hash[..],
+            full.finalize()[..8]
+        );
+
+        let decoded: Signature = Signature::decode(&sig.encode()).unwrap();
+        assert_eq!(decoded.get_strong_hash_len(), 8);
+    }
+
+    #[test]
+    fn chunked_signature_encodes_block_lengths() {
+        let input = crate::tests::pseudo_random(10_000);
+        let chunking = ContentDefinedChunking::new(256);
+        let mut sig = Signature::new(0);
+        sig.generate_chunked(&mut Cursor::new(&input), chunking)
+            .unwrap();
+        let lens = chunking.block_lens(&input);
+        assert_eq!(sig.get_blocks(), lens.len() as u64);
+        assert_eq!(sig.get_file_size(), 10_000);
+        assert_eq!(
+            sig.get_block_range(1),
+            lens[0] as u64..(lens[0] + lens[1]) as u64
+        );
+
+        let decoded: Signature = Signature::decode(&sig.encode()).unwrap();
+        assert_eq!(decoded.get_chunking(), Some(chunking));
+        assert_eq!(decoded.get_block_size(), 0);
+        assert_eq!(decoded.get_blocks(), sig.get_blocks());
+        for block_index in 0..sig.get_blocks() {
+            assert_eq!(
+                decoded.get_block_range(block_index),
+                sig.get_block_range(block_index)
+            );
+        }
+        assert_eq!(decoded.chunk_hashes, sig.chunk_hashes);
+
+        // the average size follows the header
+        let mut encoded = sig.encode();
+        for avg_size in [u64::MAX, 1 << 63, 100, 32] {
+            encoded[HEADER_LEN..HEADER_LEN + 8].copy_from_slice(&avg_size.to_be_bytes());
+            assert_eq!(
+                Signature::<Blake2b>::decode(&encoded).unwrap_err(),
+                DecodeError::InvalidBlockSize
+            );
+        }
+    }
+
+    #[test]
+    fn fixed_size_signature_rejects_empty_blocks() {
+        let mut sig = Signature::new(0);
+        let err = sig.generate(&mut Cursor::new("Obliviate")).unwrap_err();
+        assert_eq!(err, DiffError::InvalidBlockSize);
+        let err = sig
+            .generate_parallel(&mut Cursor::new("Obliviate"))
+            .unwrap_err();
+        assert_eq!(err, DiffError::InvalidBlockSize);
+        assert_eq!(sig.get_blocks(), 0);
+    }
+
+    #[test]
+    fn parallel_signature_matches_sequential() {
+        let input = crate::tests::pseudo_random(64 * 1_000);
+        let mut sequential = Signature::new(64);
+        sequential.generate(&mut Cursor::new(&input)).unwrap();
+        let mut parallel = Signature::new(64);
+        parallel
+            .generate_parallel(&mut Cursor::new(&input))
+            .unwrap();
+        assert_eq!(parallel.get_blocks(), sequential.get_blocks());
+        assert_eq!(parallel.get_file_size(), sequential.get_file_size());
+        assert_eq!(parallel.chunk_hashes, sequential.chunk_hashes);
+
+        let mut empty = Signature::new(64);
+        empty.generate_parallel(&mut Cursor::new(&[])).unwrap();
+        assert_eq!(empty.get_blocks(), 0);
+    }
+
+    #[test]
+    fn appending_matches_full_generation() {
+        let input = crate::tests::pseudo_random(1_000);
+        let mut full = Signature::new(64);
+        full.generate(&mut Cursor::new(&input)).unwrap();
+
+        // every part ends mid block
+        let mut appended = Signature::new(64);
+        appended.generate(&mut Cursor::new(&input[..100])).unwrap();
+        appended.append(&mut Cursor::new(&input[100..150])).unwrap();
+        appended.append(&mut Cursor::new(&input[150..150])).unwrap();
+        appended.append(&mut Cursor::new(&input[150..])).unwrap();
+        assert_eq!(appended.get_blocks(), full.get_blocks());
+        assert_eq!(appended.get_file_size(), full.get_file_size());
+        assert_eq!(appended.chunk_hashes, full.chunk_hashes);
+
+        // chunks as read from a stream
+        let mut streamed = Signature::new(64);
+        for chunk in input.chunks(7) {
+            streamed.append(&mut &chunk[..]).unwrap();
+        }
+        assert_eq!(streamed.chunk_hashes, full.chunk_hashes);
+
+        // the short last block of a decoded signature is not known
+        let mut decoded: Signature = Signature::decode(&full.encode()).unwrap();
+        let appending = decoded.append(&mut Cursor::new(&input[..10]));
+        assert_eq!(appending.unwrap_err(), DiffError::NotAppendable);
+        assert_eq!(decoded.get_file_size(), input.len() as u64);
+    }
 }
@@ -0,0 +1,356 @@
+use crate::{
+    diff_iter, fill_block, Add, Blake2b, Copy, Delete, Delta, DiffError, DiffIter, Digest,
+    Signature,
+};
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+const ADD: u8 = 0;
+const DELETE: u8 = 1;
+const COPY: u8 = 2;
+/// Hash of the whole new file, after the last delta
+const NEW_FILE_HASH: u8 = 3;
+/// Length of the new file, the last record of every stream
+const END: u8 = 4;
+
+/// Diffs like `diff_iter` and writes every delta to `out` as soon as it is found
+///
+/// Each delta is a tag byte, its fields as LEB128 varints and for an `Add` its content.
+/// Deltas are not coalesced, so memory use does not grow with the number of deltas. The
+/// stream ends with the length of the new file so a truncated one can be told apart. A
+/// `DiffError` is reported as `InvalidInput`.
+pub fn write_delta<W: Write>(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    mut new_buf: impl Read + Seek,
+    out: &mut W,
+) -> io::Result<()> {
+    let new_len = new_buf.seek(SeekFrom::End(0))?;
+    write_deltas(diff_iter(block_size, old_buf, new_buf), out)?;
+    write_end(new_len, out)
+}
+
+/// Same as `write_delta` against a signature of the old file, see `diff_against_signature`
+pub fn write_signature_delta<W: Write>(
+    sig: &Signature,
+    mut new_buf: impl Read + Seek,
+    out: &mut W,
+) -> io::Result<()> {
+    let new_len = new_buf.seek(SeekFrom::End(0))?;
+    write_deltas(DiffIter::with_signature(sig, new_buf), out)?;
+    write_end(new_len, out)
+}
+
+/// Same as `write_delta` with the `strong_hash` of the whole new file before the end
+///
+/// The new file is read a second time for the hash, see `apply_delta_verified`.
+pub fn write_verified_delta<W: Write>(
+    block_size: usize,
+    old_buf: Cursor<&[u8]>,
+    mut new_buf: impl Read + Seek,
+    out: &mut W,
+) -> io::Result<()> {
+    let new_len = new_buf.seek(SeekFrom::End(0))?;
+    write_deltas(diff_iter(block_size, old_buf, &mut new_buf), out)?;
+    new_buf.seek(SeekFrom::Start(0))?;
+    let mut hasher = Blake2b::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read_size = fill_block(&mut new_buf, &mut buf)?;
+        hasher.update(&buf[..read_size]);
+        if read_size < buf.len() {
+            break;
+        }
+    }
+    let hash = hasher.finalize();
+    out.write_all(&[NEW_FILE_HASH])?;
+    write_varint(out, hash.len() as u64)?;
+    out.write_all(&hash)?;
+    write_end(new_len, out)
+}
+
+fn write_deltas<W: Write>(
+    deltas: impl Iterator<Item = Result<Delta, DiffError>>,
+    out: &mut W,
+) -> io::Result<()> {
+    for delta in deltas {
+        let delta = delta.map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        encode_delta(&delta, out)?;
+    }
+    Ok(())
+}
+
+fn write_end<W: Write>(new_len: u64, out: &mut W) -> io::Result<()> {
+    out.write_all(&[END])?;
+    write_varint(out, new_len)
+}
+
+/// Reads back all deltas written by `write_delta`, any new file hash is skipped
+pub fn read_delta<R: Read>(input: &mut R) -> io::Result<Vec<Delta>> {
+    read_verified_delta(input).map(|(deltas, _)| deltas)
+}
+
+/// Reads back the deltas and the new file hash, if any, written by `write_verified_delta`
+///
+/// Reading stops at the end record, a stream without one fails with `UnexpectedEof` and
+/// one whose deltas do not add up to the new file length with `InvalidData`. The end is a
+/// record of the stream only, not a `Delta`: deltas in memory are complete by
+/// construction, and every `match` on `Delta` would have to handle a variant without bytes.
+pub fn read_verified_delta<R: Read>(input: &mut R) -> io::Result<(Vec<Delta>, Option<Vec<u8>>)> {
+    let mut deltas = Vec::new();
+    let mut new_hash = None;
+    let mut tag = [0u8];
+    loop {
+        input.read_exact(&mut tag)?;
+        let delta = match tag[0] {
+            ADD => {
+                let byte_index = read_varint(input)?;
+                let bytes = read_varint(input)?;
+                let mut content = Vec::new();
+                input.take(bytes).read_to_end(&mut content)?;
+                if content.len() as u64 != bytes {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                Delta::Add(Add {
+                    byte_index,
+                    bytes,
+                    content,
+                })
+            }
+            DELETE => Delta::Delete(Delete {
+                byte_index: read_varint(input)?,
+                bytes: read_varint(input)?,
+            }),
+            COPY => Delta::Copy(Copy {
+                src_byte_index: read_varint(input)?,
+                bytes: read_varint(input)?,
+                block_index: read_varint(input)?,
+            }),
+            NEW_FILE_HASH => {
+                let len = read_varint(input)? as usize;
+                if len > Blake2b::output_size() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "new file hash too long",
+                    ));
+                }
+                let mut hash = vec![0u8; len];
+                input.read_exact(&mut hash)?;
+                new_hash = Some(hash);
+                continue;
+            }
+            END => {
+                let new_len = read_varint(input)?;
+                let written: u64 = deltas
+                    .iter()
+                    .filter(|delta| !matches!(delta, Delta::Delete(_)))
+                    .map(Delta::bytes)
+                    .sum();
+                if written != new_len {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "deltas do not add up to the new file length",
+                    ));
+                }
+                return Ok((deltas, new_hash));
+            }
+            tag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown delta tag {}", tag),
+                ))
+            }
+        };
+        deltas.push(delta);
+    }
+}
+
+fn encode_delta<W: Write>(delta: &Delta, out: &mut W) -> io::Result<()> {
+    match delta {
+        Delta::Add(add) => {
+            out.write_all(&[ADD])?;
+            write_varint(out, add.byte_index)?;
+            write_varint(out, add.bytes)?;
+            out.write_all(&add.content)
+        }
+        Delta::Delete(delete) => {
+            out.write_all(&[DELETE])?;
+            write_varint(out, delete.byte_index)?;
+            write_varint(out, delete.bytes)
+        }
+        Delta::Copy(copy) => {
+            out.write_all(&[COPY])?;
+            write_varint(out, copy.src_byte_index)?;
+            write_varint(out, copy.bytes)?;
+            write_varint(out, copy.block_index)
+        }
+    }
+}
+
+/// 7 bits at a time from the lowest, the high bit is set while more follow
+fn write_varint<W: Write>(out: &mut W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    out.write_all(&buf[..len])
+}
+
+fn read_varint<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        input.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        "varint longer than 64 bits",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::corpus;
+    use crate::{apply_delta, apply_delta_verified, VerifyError};
+
+    #[test]
+    fn delta_stream_round_trips() {
+        for (block_size, old, new) in corpus() {
+            let mut out = Vec::new();
+            write_delta(block_size, Cursor::new(&old), Cursor::new(&new), &mut out).unwrap();
+            let deltas = read_delta(&mut Cursor::new(&out)).unwrap();
+
+            let expected: Vec<Delta> = diff_iter(block_size, Cursor::new(&old), Cursor::new(&new))
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(deltas, expected);
+            assert_eq!(apply_delta(&old, block_size, &deltas), new);
+        }
+    }
+
+    #[test]
+    fn signature_delta_stream_matches_delta_stream() {
+        for (block_size, old, new) in corpus() {
+            let mut sig = Signature::new(block_size);
+            sig.generate(&mut Cursor::new(&old)).unwrap();
+            let mut from_sig = Vec::new();
+            write_signature_delta(&sig, Cursor::new(&new), &mut from_sig).unwrap();
+            let mut from_old = Vec::new();
+            write_delta(
+                block_size,
+                Cursor::new(&old),
+                Cursor::new(&new),
+                &mut from_old,
+            )
+            .unwrap();
+            assert_eq!(from_sig, from_old);
+        }
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, 1 << 40, u64::MAX].iter() {
+            let mut out = Vec::new();
+            write_varint(&mut out, *value).unwrap();
+            assert_eq!(read_varint(&mut Cursor::new(&out)).unwrap(), *value);
+        }
+        let mut out = Vec::new();
+        write_varint(&mut out, 300).unwrap();
+        assert_eq!(out, vec![0xac, 0x02]);
+    }
+
+    #[test]
+    fn truncated_delta_stream_fails() {
+        let (block_size, old, new) = corpus().remove(0);
+        let mut out = Vec::new();
+        write_delta(block_size, Cursor::new(&old), Cursor::new(&new), &mut out).unwrap();
+        out.pop();
+        let err = read_delta(&mut Cursor::new(&out)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let err = read_delta(&mut Cursor::new(&[7u8])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn delta_streams_need_their_end() {
+        let block_size = 16;
+        let old = crate::tests::pseudo_random(1_000);
+        let mut new = old.clone();
+        new.splice(500..500, b"Lumos".iter().cloned());
+        let mut out = Vec::new();
+        write_delta(block_size, Cursor::new(&old), Cursor::new(&new), &mut out).unwrap();
+        let mut end = Vec::new();
+        write_end(new.len() as u64, &mut end).unwrap();
+        assert!(out.ends_with(&end));
+
+        // cut right before the end record, between two deltas, and inside it
+        let cut = &out[..out.len() - end.len()];
+        let err = read_delta(&mut Cursor::new(cut)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(
+            end.len() > 2,
+            "the new file length takes more than a varint byte"
+        );
+        for inside in 1..end.len() {
+            let cut = &out[..out.len() - end.len() + inside];
+            let err = read_delta(&mut Cursor::new(cut)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+        // whatever follows the end is left unread, e.g. the next message on a socket
+        let mut followed = Cursor::new([&out[..], b"Nox"].concat());
+        assert_eq!(
+            read_delta(&mut followed).unwrap().len(),
+            read_delta(&mut Cursor::new(&out)).unwrap().len()
+        );
+        assert_eq!(followed.position(), out.len() as u64);
+
+        // an end of the wrong length
+        let mut wrong = cut.to_vec();
+        write_end(new.len() as u64 + 1, &mut wrong).unwrap();
+        let err = read_delta(&mut Cursor::new(&wrong)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn verified_delta_detects_corrupted_content() {
+        let (block_size, old, new) = corpus().remove(2);
+        let mut out = Vec::new();
+        write_verified_delta(block_size, Cursor::new(&old), Cursor::new(&new), &mut out).unwrap();
+        let (mut deltas, new_hash) = read_verified_delta(&mut Cursor::new(&out)).unwrap();
+        let new_hash = new_hash.unwrap();
+        assert_eq!(
+            apply_delta_verified(&old, block_size, &deltas, &new_hash).unwrap(),
+            new
+        );
+        // the hash is skipped by read_delta
+        assert_eq!(
+            read_delta(&mut Cursor::new(&out)).unwrap().len(),
+            deltas.len()
+        );
+
+        for delta in deltas.iter_mut() {
+            if let Delta::Add(add) = delta {
+                add.content[0] ^= 1;
+                break;
+            }
+        }
+        let err: VerifyError =
+            apply_delta_verified(&old, block_size, &deltas, &new_hash).unwrap_err();
+        assert_eq!(err.expected, new_hash);
+        assert_ne!(err.got, new_hash);
+    }
+}
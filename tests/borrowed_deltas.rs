@@ -0,0 +1,47 @@
+use rolling_hash::{check_diffs, check_diffs_borrowed};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts the bytes ever allocated, this is the only test of the binary so nothing else adds up
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocated_by(f: impl FnOnce()) -> usize {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    f();
+    ALLOCATED.load(Ordering::SeqCst) - before
+}
+
+#[test]
+fn borrowed_deltas_do_not_allocate_their_content() {
+    let old: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 31 % 251) as u8).collect();
+    let mut new = old.clone();
+    let inserted: Vec<u8> = (0..1 << 20u32).map(|i| (i * 7 % 253) as u8).collect();
+    new.splice(32 * 1024..32 * 1024, inserted.iter().cloned());
+
+    let borrowed = allocated_by(|| {
+        check_diffs_borrowed(64, Cursor::new(&old), &new).unwrap();
+    });
+    let owned = allocated_by(|| {
+        check_diffs(64, Cursor::new(&old), Cursor::new(&new)).unwrap();
+    });
+    // the signature and the deltas themselves are allocated either way
+    assert!(owned - borrowed >= inserted.len());
+    assert!(borrowed < inserted.len(), "{} bytes allocated", borrowed);
+}
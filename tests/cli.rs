@@ -0,0 +1,55 @@
+use rolling_hash::Signature;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+/// Unique per test run, so runs of the suite side by side do not share files
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("rolling_hash_cli_{}_{}", process::id(), name))
+}
+
+fn run(args: &[&PathBuf], subcommand: &str) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_rolling_hash"))
+        .arg(subcommand)
+        .args(args)
+        .status()
+        .unwrap()
+        .success()
+}
+
+#[test]
+fn signature_delta_and_patch_reproduce_the_new_file() {
+    let (old, new, sig, delta, out) = (
+        temp_path("old"),
+        temp_path("new"),
+        temp_path("sig"),
+        temp_path("delta"),
+        temp_path("out"),
+    );
+    let old_content = "It does not do to dwell on dreams and forget to live. ".repeat(50);
+    let new_content = old_content.replacen("dreams", "schemes", 3) + "Remember that.";
+    fs::write(&old, &old_content).unwrap();
+    fs::write(&new, &new_content).unwrap();
+
+    assert!(run(&[&old, &sig], "signature"));
+    assert!(run(&[&sig, &new, &delta], "delta"));
+    assert!(run(&[&old, &delta, &out], "patch"));
+    let signature: Signature = Signature::decode(&fs::read(&sig).unwrap()).unwrap();
+    let patched = fs::read(&out).unwrap();
+    for path in [&old, &new, &sig, &delta, &out] {
+        fs::remove_file(path).unwrap();
+    }
+
+    assert_eq!(signature.get_file_size(), old_content.len() as u64);
+    assert_eq!(patched, new_content.as_bytes());
+}
+
+#[test]
+fn unknown_subcommands_fail() {
+    assert!(!run(&[], "rsync"));
+    assert!(!run(
+        &[&temp_path("missing"), &temp_path("missing_sig")],
+        "signature"
+    ));
+}
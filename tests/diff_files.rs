@@ -0,0 +1,36 @@
+use rolling_hash::{diff_files, Delta};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+
+#[test]
+fn diff_files_reads_and_diffs_both_files() {
+    let old = env::temp_dir().join("rolling_hash_diff_files_old");
+    let new = env::temp_dir().join("rolling_hash_diff_files_new");
+    fs::write(&old, "Anyone can speak Troll. All you have to do is grunt.").unwrap();
+    fs::write(
+        &new,
+        "Anyone can speak Troll. All you have to not do is grunt.",
+    )
+    .unwrap();
+
+    let diffs = diff_files(&old, &new, 4);
+    fs::remove_file(&old).unwrap();
+    fs::remove_file(&new).unwrap();
+
+    let diffs = diffs.unwrap();
+    let adds: Vec<&Delta> = diffs
+        .iter()
+        .filter(|delta| matches!(delta, Delta::Add(_)))
+        .collect();
+    assert_eq!(adds.len(), 1);
+    assert!(!diffs.iter().any(|delta| matches!(delta, Delta::Delete(_))));
+}
+
+#[test]
+fn diff_files_reports_missing_files() {
+    let old = env::temp_dir().join("rolling_hash_diff_files_missing_old");
+    let new = env::temp_dir().join("rolling_hash_diff_files_missing_new");
+    let err = diff_files(&old, &new, 4).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}